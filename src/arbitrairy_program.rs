@@ -1,104 +1,293 @@
+use std::ops::Range;
+
 use bit_vec::BitVec;
-use itertools::{repeat_n, Itertools};
+use itertools::Itertools;
 use rand::Rng;
+#[cfg(test)]
+use rand::seq::SliceRandom;
+
+use crate::circuit::{ReversibleCircuit, VerifyError};
+use crate::crossover::Crossover;
+use crate::serialization::{self, ByteReader, FormatError, Header, Kind};
 
 /// Arbitrairy isomorphic mapping of {bit vecs of inp_size} to itself
 /// Technically speaking any program can be one of these, it'd just wildly impractical
-#[derive(Clone, Debug)]
+/// Bit vecs are taken as numbers with their first bit the most significant, so the tables stay small enough to copy around freely
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 struct SBox<const InpSize: usize, const TwoToInpSize: usize> {
-    shuffles: [BitVec; TwoToInpSize]
+    shuffles: [u16; TwoToInpSize],
+    inverses: [u16; TwoToInpSize] // shuffles[n] == m <=> inverses[m] == n, kept in sync so going backwards is just as cheap
 }
 
 impl<const InpSize: usize, const TwoToInpSize: usize> SBox<InpSize, TwoToInpSize> {
     fn new() -> Self {
         assert_eq!(TwoToInpSize, 1 << InpSize);
+        assert!(InpSize <= 16, "SBox entries are u16s");
+        let identity = std::array::from_fn(|n| n as u16);
         Self {
-            shuffles: (0..(1<<InpSize)).map(|n: u64| Self::num_to_bitvec(n)).collect_array().unwrap().try_into().unwrap()
+            shuffles: identity,
+            inverses: identity
         }
     }
 
-    fn forward(&self, input: BitVec) -> BitVec {
-        assert!(input.len() <= InpSize);
-        self.shuffles[Self::bitvec_to_num(&input) as usize].clone()
+    fn forward(&self, input: usize) -> usize {
+        self.shuffles[input] as usize
     }
 
-    pub fn mutation(&self, mut_rate: f64) -> Self {
+    /// Undoes `forward`, ie finds which input would have been shuffled into `output`
+    pub fn inverse(&self, output: usize) -> usize {
+        self.inverses[output] as usize
+    }
+
+    pub fn mutation(&self, mut_rate: f64, rng: &mut impl Rng) -> Self {
         let mut new = self.clone();
-        for _ in 0..rand::rng().sample(rand_distr::Binomial::new((1<<InpSize) as u64, mut_rate).unwrap()) {
-            let a = rand::rng().random_range(0..new.shuffles.len());
-            let b = rand::rng().random_range(0..new.shuffles.len());
+        for _ in 0..rng.sample(rand_distr::Binomial::new((1<<InpSize) as u64, mut_rate).unwrap()) {
+            let a = rng.random_range(0..new.shuffles.len());
+            let b = rng.random_range(0..new.shuffles.len());
             new.shuffles.swap(a, b);
+            // The two entries that moved now need to point back at their new positions
+            for pos in [a, b] {
+                new.inverses[new.shuffles[pos] as usize] = pos as u16;
+            }
         }
         new
     }
 
-    fn num_to_bitvec(n: u64) -> BitVec {
-        let mut v = BitVec::from_bytes(&n.to_be_bytes());
-        v = v.split_off(v.len() - InpSize); // Only get the end that we want
-        v
+    /// Builds the SBox that sends n to perm[n], or None if perm isn't a permutation of 0..2^InpSize
+    fn from_permutation(perm: &[u64]) -> Option<Self> {
+        if !perm.iter().copied().sorted().eq(0..TwoToInpSize as u64) {
+            return None;
+        }
+        let mut new = Self::new();
+        perm.iter().enumerate().for_each(|(n, &m)| {
+            new.shuffles[n] = m as u16;
+            new.inverses[m as usize] = n as u16;
+        });
+        Some(new)
     }
-    fn bitvec_to_num(bitvec: &BitVec) -> u64 {
-        let padded: BitVec = repeat_n(false, 8*8 - bitvec.len()).chain(bitvec).collect();
-        u64::from_be_bytes(padded.to_bytes().try_into().unwrap())
+
+    /// Whether `shuffles` is a permutation and `inverses` really is its inverse
+    fn verify(&self) -> Result<(), String> {
+        if !self.permutation().sorted().eq(0..TwoToInpSize as u64) {
+            return Err(format!("SBox isn't a permutation of 0..{TwoToInpSize}"));
+        }
+        if let Some(n) = (0..TwoToInpSize).find(|&n| self.inverse(self.forward(n)) != n) {
+            return Err(format!("SBox's inverse is wrong at {n}"));
+        }
+        Ok(())
     }
+
+    /// `forward` on 64 inputs at once, `lanes[k]` holding the k'th input bit of each. Every output bit is the OR of the minterms
+    /// (ANDs of every input bit or its negation) of the inputs that set it
+    fn forward_sliced(&self, lanes: &mut [u64; InpSize]) {
+        // Built up an input bit at a time, so minterms[m] is set in the lanes whose input is m (the first bit being the most significant)
+        let mut minterms = [0u64; TwoToInpSize];
+        minterms[0] = u64::MAX;
+        for (k, &x) in lanes.iter().enumerate() {
+            // Backwards so each minterm is split before anything's written over it
+            for t in (0..1 << k).rev() {
+                let m = minterms[t];
+                minterms[2 * t] = m & !x;
+                minterms[2 * t + 1] = m & x;
+            }
+        }
+        *lanes = [0; InpSize];
+        for (&out, minterm) in self.shuffles.iter().zip(minterms) {
+            for (k, lane) in lanes.iter_mut().enumerate() {
+                if out >> (InpSize - 1 - k) & 1 == 1 {
+                    *lane |= minterm;
+                }
+            }
+        }
+    }
+
+    fn permutation(&self) -> impl Iterator<Item = u64> + '_ {
+        self.shuffles.iter().map(|&n| n as u64)
+    }
+}
+
+/// The bits on `wires` as a number, the first wire's bit being the most significant
+fn gather<const N: usize>(mem: &BitVec, wires: &[usize; N]) -> usize {
+    wires.iter().fold(0, |n, &wire| n << 1 | mem[wire] as usize)
+}
+
+/// Undoes `gather`, setting `wires` to the bits of `n`
+fn scatter<const N: usize>(mem: &mut BitVec, wires: &[usize; N], n: usize) {
+    wires.iter().enumerate().for_each(|(k, &wire)| mem.set(wire, n >> (N - 1 - k) & 1 == 1));
 }
 
+#[derive(Clone, Hash)]
 pub struct Program<const GateSize: usize, const TwoToGateSize: usize> {
     gates: Vec<(SBox<GateSize, TwoToGateSize>, [usize; GateSize])>,
     inp_size: usize
 }
 
 impl<const GateSize: usize, const TwoToGateSize: usize> Program<GateSize, TwoToGateSize> {
-    pub fn new(inp_size: usize) -> Self {
+    fn header(&self) -> Header {
+        Header { kind: Kind::Arbitrairy, gate_size: GateSize, inp_size: self.inp_size, gates: self.gates.len() }
+    }
+
+    fn gate_from_parts(connections: &[usize], perm: &[u64], inp_size: usize, gate: usize) -> Result<(SBox<GateSize, TwoToGateSize>, [usize; GateSize]), FormatError> {
+        let connections: [usize; GateSize] = connections.try_into().map_err(|_| FormatError::InvalidGate { gate, reason: format!("Expected {GateSize} wires but found {}", connections.len()) })?;
+        serialization::check_wires(&connections, inp_size, gate)?;
+        let sbox = SBox::from_permutation(perm).ok_or_else(|| FormatError::InvalidGate { gate, reason: format!("SBox isn't a permutation of 0..{TwoToGateSize}") })?;
+        Ok((sbox, connections))
+    }
+
+    fn rectify_duplicates(&self, connections: &mut [usize; GateSize]) {
+        // If they're all the same that breaks reversibility so... don't allow that
+        for i in 0..GateSize {
+            let mut delta: i64 = 1; // Goes in the pattern 1, -2, 3, -4, 5, -6; the sum of wherever you stops probes gradually away from the initial pos
+            while connections.iter().filter(|c| **c == connections[i]).count() > 1 {
+                //println!("{} {} {} {} {}", connections[i] as i64, connections[i] as i64 + delta, (connections[i] as i64 + delta) % (GateSize as i64), ((connections[i] as i64 + delta) % GateSize as i64) as usize, 80000i64 % 3i64);
+                connections[i] = ((connections[i] as i64 + delta).rem_euclid(self.inp_size as i64)) as usize; // Wrapping within inp_size, not GateSize, which piled every wire it touched onto the first few
+                delta = -(delta + delta.signum());
+            }
+        }
+    }
+}
+
+impl<const GateSize: usize, const TwoToGateSize: usize> ReversibleCircuit for Program<GateSize, TwoToGateSize> {
+    #[cfg(test)]
+    const GATE_WIRES: usize = GateSize;
+
+    fn new(inp_size: usize) -> Self {
         Self {
             gates: vec![(SBox::new(), (0..GateSize).collect_vec().try_into().unwrap())],
             inp_size
         }
     }
 
-    pub fn forward(&self, mut input: BitVec) -> BitVec {
+    #[cfg(test)]
+    fn random(inp_size: usize, gates: usize, rng: &mut impl Rng) -> Self {
+        assert!(inp_size >= GateSize);
+        let gates = (0..gates).map(|_| {
+            let mut perm = (0..TwoToGateSize as u64).collect_vec();
+            perm.shuffle(rng);
+            let connections = rand::seq::index::sample(rng, inp_size, GateSize).into_iter().collect_array().unwrap();
+            (SBox::from_permutation(&perm).unwrap(), connections)
+        }).collect();
+        Self { gates, inp_size }
+    }
+
+    fn forward(&self, mut input: BitVec) -> BitVec {
         assert!(input.len() <= self.inp_size);
         while input.len() < self.inp_size {
             //input.push(input.len() % 2 == 0);
-            input.push(false);
+            input.push(Self::padding(input.len()));
         }
         let mut mem = input; // It was confusing to have it be called "input"
         for (shuf_op, connections) in &self.gates {
-            let inp: BitVec = BitVec::from_iter(connections.map(|i| mem[i]));
-            let out = shuf_op.forward(inp);
-            out.iter().zip_eq(connections).for_each(|(val, &wire)| mem.set(wire, val));
+            let out = shuf_op.forward(gather(&mem, connections));
+            scatter(&mut mem, connections, out);
         }
         mem
     }
 
-    fn rectify_duplicates(&self, connections: &mut [usize; GateSize]) {
-        // If they're all the same that breaks reversibility so... don't allow that
-        for i in 0..GateSize {
-            let mut delta: i64 = 1; // Goes in the pattern 1, -2, 3, -4, 5, -6; the sum of wherever you stops probes gradually away from the initial pos
-            while connections.iter().filter(|c| **c == connections[i]).count() > 1 {
-                //println!("{} {} {} {} {}", connections[i] as i64, connections[i] as i64 + delta, (connections[i] as i64 + delta) % (GateSize as i64), ((connections[i] as i64 + delta) % GateSize as i64) as usize, 80000i64 % 3i64);
-                connections[i] = ((connections[i] as i64 + delta).rem_euclid(GateSize as i64)) as usize;
-                delta = -(delta + delta.signum());
-            }
+    fn padding(_wire: usize) -> bool {
+        false
+    }
+
+    fn forward_sliced(&self, wires: &mut [u64], gates: Range<usize>) {
+        assert_eq!(wires.len(), self.inp_size);
+        for (shuf_op, connections) in &self.gates[gates] {
+            let mut lanes = connections.map(|i| wires[i]);
+            shuf_op.forward_sliced(&mut lanes);
+            lanes.iter().zip_eq(connections).for_each(|(&lane, &wire)| wires[wire] = lane);
+        }
+    }
+
+    /// Runs the gates in reverse, each through its SBox's inverse, so that `backward(forward(x)) == x` (given x was already padded to `inp_size`)
+    fn backward(&self, output: BitVec) -> BitVec {
+        assert_eq!(output.len(), self.inp_size);
+        let mut mem = output;
+        for (shuf_op, connections) in self.gates.iter().rev() {
+            let out = shuf_op.inverse(gather(&mem, connections));
+            scatter(&mut mem, connections, out);
+        }
+        mem
+    }
+
+    fn inp_size(&self) -> usize {
+        self.inp_size
+    }
+
+    fn gate_count(&self) -> usize {
+        self.gates.len()
+    }
+
+    fn shared_prefix(&self, other: &Self) -> usize {
+        self.gates.iter().zip(&other.gates).take_while(|(a, b)| a == b).count()
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        serialization::write_header(&mut out, self.header());
+        for (sbox, connections) in &self.gates {
+            connections.iter().for_each(|&c| out.extend_from_slice(&(c as u32).to_le_bytes()));
+            sbox.permutation().for_each(|n| out.extend_from_slice(&(n as u16).to_le_bytes()));
+        }
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        let mut reader = ByteReader::new(bytes);
+        let header = serialization::read_header(&mut reader, Kind::Arbitrairy, GateSize)?;
+        let gates = (0..header.gates).map(|gate| {
+            let connections: Vec<usize> = (0..GateSize).map(|_| reader.u32().map(|c| c as usize)).try_collect()?;
+            let perm: Vec<u64> = (0..TwoToGateSize).map(|_| reader.u16().map(u64::from)).try_collect()?;
+            Self::gate_from_parts(&connections, &perm, header.inp_size, gate)
+        }).try_collect()?;
+        reader.finish()?;
+        Ok(Self { gates, inp_size: header.inp_size })
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = serialization::write_text_header(self.header());
+        for (sbox, connections) in &self.gates {
+            text += &format!("gate {} | {}\n", connections.iter().join(" "), sbox.permutation().join(" "));
+        }
+        text
+    }
+
+    fn from_text(text: &str) -> Result<Self, FormatError> {
+        let (header, lines) = serialization::read_text(text, Kind::Arbitrairy, GateSize)?;
+        let gates = lines.into_iter().enumerate().map(|(gate, (line, contents))| {
+            let (wires, perm) = contents.split_once('|').ok_or_else(|| FormatError::BadLine { line, reason: "Gate is missing the `|` between its wires and SBox".to_string() })?;
+            Self::gate_from_parts(&serialization::parse_all(wires, line)?, &serialization::parse_all(perm, line)?, header.inp_size, gate)
+        }).try_collect()?;
+        Ok(Self { gates, inp_size: header.inp_size })
+    }
+
+    /// Checks every gate's wires are distinct and within inp_size, and that its SBox is a permutation with matching inverses
+    fn verify_gates(&self) -> Result<(), VerifyError> {
+        for (gate, (sbox, connections)) in self.gates.iter().enumerate() {
+            serialization::check_wires(connections, self.inp_size, gate)?;
+            sbox.verify().map_err(|reason| FormatError::InvalidGate { gate, reason })?;
         }
+        Ok(())
     }
 
-    pub fn mutation(&self, mut_rate: f64) -> Self {
-        let mut rng = rand::rng();
+    fn crossover(&self, other: &Self, crossover: Crossover, rng: &mut impl Rng) -> Self {
+        assert_eq!(self.inp_size, other.inp_size);
+        Self { gates: crossover.splice(&self.gates, &other.gates, |(_, connections)| *connections, rng), inp_size: self.inp_size }
+    }
+
+    fn mutate(&self, mut_rate: f64, rng: &mut impl Rng) -> Self {
         let mut gates = self.gates.clone();
 
 
         for _ in 0..rng.sample(rand_distr::Binomial::new(gates.len() as u64 + 1, mut_rate).unwrap()) {
             // I feel like this should be able to be more compact
             let middle = rng.random_range(0..gates.len());
-            let size = std::cmp::min(rand::rng().sample(rand_distr::Normal::new(4.0, 4.0).unwrap()) as usize, std::cmp::min(gates.len() - middle - 1, middle - 0),
+            let size = std::cmp::min(rng.sample(rand_distr::Normal::new(4.0, 4.0).unwrap()) as usize, std::cmp::min(gates.len() - middle - 1, middle - 0),
             );
             //println!("Mid is {}, {}", middle, size);
 
 
             match rng.random_range(1..=5) {
                 1 => { // Shift pos of group, vertical
-                    let shamt = rand::rng().sample(rand_distr::Normal::new(0.0, 3.0).unwrap()) as i64;
+                    let shamt = rng.sample(rand_distr::Normal::new(0.0, 3.0).unwrap()) as i64;
                     for gate in gates[middle - size..=middle + size].iter_mut() {
                         for conn in &mut gate.1 {
                             *conn = (*conn as i64 + shamt).clamp(0, self.inp_size as i64 - 1) as usize;
@@ -107,7 +296,7 @@ impl<const GateSize: usize, const TwoToGateSize: usize> Program<GateSize, TwoToG
                     }
                 }
                 2 => { // Horizontal copy of group
-                    let shamt = rand::rng().sample(rand_distr::Normal::new(0.0, 10.0 + (size * 4) as f64).unwrap()) as i64;
+                    let shamt = rng.sample(rand_distr::Normal::new(0.0, 10.0 + (size * 4) as f64).unwrap()) as i64;
                     let excerpt = gates[middle - size..=middle + size].to_owned();
                     let insertion_point = (middle as i64 + shamt + size as i64 * shamt.signum()).clamp(0, gates.len() as i64 - 1) as usize;
                     let tmp = gates.splice(insertion_point..=insertion_point, excerpt).next().unwrap();
@@ -126,14 +315,14 @@ impl<const GateSize: usize, const TwoToGateSize: usize> Program<GateSize, TwoToG
                     let dist = rand_distr::Normal::new(0.0, 16.0).unwrap();
                     for gate in gates[middle - size..=middle + size].iter_mut() {
                         for conn in &mut gate.1 {
-                            *conn = (*conn as i64 + rand::rng().sample(dist) as i64).clamp(0, self.inp_size as i64 - 1) as usize;
+                            *conn = (*conn as i64 + rng.sample(dist) as i64).clamp(0, self.inp_size as i64 - 1) as usize;
                         }
                         self.rectify_duplicates(&mut gate.1);
                     }
                 },
                 5 => { // Mutate the inside of an the SBoxes
                     for gate in gates[middle - size..=middle + size].iter_mut() {
-                        let new_sbox = gate.0.mutation(mut_rate);
+                        let new_sbox = gate.0.mutation(mut_rate, rng);
                         gate.0 = new_sbox;
                     }
                 }
@@ -150,7 +339,208 @@ impl<const GateSize: usize, const TwoToGateSize: usize> Program<GateSize, TwoToG
         Self { gates, inp_size: self.inp_size}
     }
 
-    pub fn complexity(&self) -> i64 {
+    fn complexity(&self) -> i64 {
         self.gates.len() as i64
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::circuit::{ReversibleCircuit, VerifyError};
+    use crate::crossover::{Alignment, Points};
+
+    fn random_input(len: usize, rng: &mut impl Rng) -> BitVec {
+        (0..len).map(|_| rng.random_bool(0.5)).collect()
+    }
+
+    #[test]
+    fn sbox_inverse_undoes_forward() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut sbox = SBox::<4, 16>::new();
+        for _ in 0..50 {
+            sbox = sbox.mutation(0.5, &mut rng);
+            for n in 0..16 {
+                assert_eq!(sbox.inverse(sbox.forward(n)), n);
+            }
+        }
+    }
+
+    #[test]
+    fn inverses_stay_in_sync() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut sbox = SBox::<4, 16>::new();
+        for _ in 0..50 {
+            sbox = sbox.mutation(0.5, &mut rng);
+            for (n, &shuffled) in sbox.shuffles.iter().enumerate() {
+                assert_eq!(sbox.inverses[shuffled as usize] as usize, n);
+            }
+        }
+    }
+
+    /// The first wire listed is the most significant bit, both ways
+    #[test]
+    fn gathers_and_scatters_wires() {
+        let mut mem = BitVec::from_fn(8, |i| i == 1 || i == 6);
+        assert_eq!(gather(&mem, &[6, 0, 1]), 0b101);
+        scatter(&mut mem, &[3, 6, 0], 0b011);
+        assert_eq!(mem, BitVec::from_fn(8, |i| [0, 1, 6].contains(&i)));
+    }
+
+    /// Grows a lineage of randomly mutated programs and checks that every one of them can be run backwards
+    #[test]
+    fn backward_undoes_forward() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut p = Program::<4, 16>::new(400);
+        for _ in 0..100 {
+            p = p.mutate(0.3, &mut rng);
+            for _ in 0..4 {
+                let input = random_input(400, &mut rng);
+                assert_eq!(p.backward(p.forward(input.clone())), input, "backward(forward(x)) != x");
+            }
+        }
+    }
+
+    /// Short inputs get padded with zeros, which is what comes back out
+    #[test]
+    fn backward_gives_the_padded_input() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut p = Program::<4, 16>::new(64);
+        for _ in 0..50 {
+            p = p.mutate(0.3, &mut rng);
+            let input = random_input(40, &mut rng);
+            let back = p.backward(p.forward(input.clone()));
+            assert!(back.iter().take(40).eq(&input) && back.iter().skip(40).all(|b| !b));
+        }
+    }
+
+    /// Saves and reloads a lineage of mutated programs in both forms, checking they come back identical and damaged files are refused
+    #[test]
+    fn saves_and_reloads() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut p = Program::<4, 16>::new(400);
+        for _ in 0..50 {
+            p = p.mutate(0.3, &mut rng);
+            let bytes = p.to_bytes();
+            assert_eq!(Program::<4, 16>::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+            assert_eq!(Program::<4, 16>::from_text(&p.to_text()).unwrap().to_bytes(), bytes);
+            assert!(Program::<4, 16>::from_bytes(&bytes[..rng.random_range(0..bytes.len())]).is_err(), "Truncated program was accepted");
+            assert!(matches!(Program::<3, 8>::from_bytes(&bytes), Err(FormatError::WrongGateSize { expected: 3, found: 4 })));
+            assert!(matches!(Program::<3, 8>::from_text(&p.to_text()), Err(FormatError::WrongGateSize { expected: 3, found: 4 })));
+        }
+    }
+
+    #[test]
+    fn refuses_broken_gates() {
+        let header = "reversible-program 1\nkind arbitrairy\ngate_size 2\ninp_size 8\n";
+        assert!(Program::<2, 4>::from_text(&format!("{header}gate 0 7 | 3 1 0 2")).is_ok());
+        for gate in ["0 8 | 3 1 0 2", "5 5 | 3 1 0 2", "0 1 2 | 3 1 0 2", "0 1 | 3 1 0 0", "0 1 | 0 1 2", "0 1 | 0 1 2 3 4"] {
+            assert!(matches!(Program::<2, 4>::from_text(&format!("{header}gate {gate}")), Err(FormatError::InvalidGate { gate: 0, .. })), "Accepted {gate}");
+        }
+        assert!(matches!(Program::<2, 4>::from_text(&format!("{header}gate 0 1 3 1 0 2")), Err(FormatError::BadLine { line: 5, .. })));
+    }
+
+    #[test]
+    fn mutation_only_depends_on_the_rng() {
+        let mutate = |seed| {
+            let mut rng = StdRng::seed_from_u64(seed);
+            (0..20).fold(Program::<4, 16>::new(400), |p, _| p.mutate(0.3, &mut rng)).to_bytes()
+        };
+        assert_eq!(mutate(1), mutate(1));
+        assert_ne!(mutate(1), mutate(2));
+    }
+
+    /// Crosses two separately mutated lineages every way there is, checking the children are still valid reversible programs
+    #[test]
+    fn crossover_children_are_valid() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let (mut a, mut b) = (Program::<4, 16>::new(400), Program::<4, 16>::new(400));
+        for _ in 0..50 {
+            a = a.mutate(0.3, &mut rng);
+            b = b.mutate(0.3, &mut rng);
+            for points in [Points::One, Points::Two] {
+                for alignment in [Alignment::Position, Alignment::Wires] {
+                    let child = a.crossover(&b, Crossover { points, alignment }, &mut rng).mutate(0.3, &mut rng);
+                    // Reloading checks every gate's wires are distinct and in range
+                    assert_eq!(Program::<4, 16>::from_bytes(&child.to_bytes()).unwrap().to_bytes(), child.to_bytes());
+                    let input = random_input(400, &mut rng);
+                    assert_eq!(child.backward(child.forward(input.clone())), input);
+                }
+            }
+        }
+    }
+
+    /// Small enough that `verify` tries every input, so reversibility is checked exactly rather than sampled
+    #[test]
+    fn mutated_programs_verify_exhaustively() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let (mut a, mut b) = (Program::<4, 16>::new(10), Program::<4, 16>::new(10));
+        for _ in 0..50 {
+            a = a.mutate(0.3, &mut rng);
+            b = b.mutate(0.3, &mut rng).crossover(&a, Crossover { points: Points::Two, alignment: Alignment::Wires }, &mut rng);
+            for p in [&a, &b] {
+                assert!(p.verify().unwrap_or_else(|e| panic!("{e} in\n{}", p.to_text())), "Didn't check every input");
+            }
+        }
+        assert!(!Program::<4, 16>::new(crate::circuit::EXHAUSTIVE_LIMIT + 1).verify().unwrap());
+    }
+
+    #[test]
+    fn verify_catches_broken_sboxes() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let p = (0..20).fold(Program::<4, 16>::new(10), |p, _| p.mutate(0.3, &mut rng));
+        let mut stale = p.clone();
+        stale.gates[0].0.inverses.swap(0, 1);
+        let mut collides = p.clone();
+        collides.gates[0].0.shuffles[0] = collides.gates[0].0.shuffles[1];
+        let mut repeats = p.clone();
+        repeats.gates[0].1[1] = repeats.gates[0].1[0];
+        for broken in [stale, collides, repeats] {
+            assert!(matches!(broken.verify(), Err(VerifyError::BadGate(FormatError::InvalidGate { gate: 0, .. }))));
+        }
+    }
+
+    /// Wires that clash are moved to the nearest free ones, not wrapped round onto the first GateSize
+    #[test]
+    fn rectified_wires_stay_nearby() {
+        let mut connections = [200; 4];
+        Program::<4, 16>::new(400).rectify_duplicates(&mut connections);
+        assert!(connections.iter().all_unique(), "{connections:?}");
+        assert!(connections.iter().all(|c| (197..=203).contains(c)), "{connections:?}");
+        let mut connections = [0, 0, 2];
+        Program::<3, 8>::new(3).rectify_duplicates(&mut connections);
+        assert!(connections.iter().sorted().eq(&[0, 1, 2]), "{connections:?}");
+    }
+
+    #[test]
+    fn random_programs_are_valid() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for inp_size in [3, 4, 10] {
+            let p = Program::<3, 8>::random(inp_size, 20, &mut rng);
+            assert_eq!(p.gates.len(), 20);
+            assert!(p.verify().unwrap());
+        }
+    }
+
+    /// Including short inputs, which get padded, and batches that don't fill all 64 lanes
+    fn forward_many_agrees_with_forward<const G: usize, const T: usize>(inp_size: usize) {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut p = Program::<G, T>::random(inp_size, 10, &mut rng);
+        for _ in 0..20 {
+            p = p.mutate(0.3, &mut rng);
+            let inputs: Vec<BitVec> = (0..rng.random_range(0..=150)).map(|_| random_input(rng.random_range(0..=inp_size), &mut rng)).collect();
+            let expected: Vec<BitVec> = inputs.iter().map(|i| p.forward(i.clone())).collect();
+            assert!(p.forward_many(&inputs) == expected, "forward_many disagreed with forward");
+        }
+    }
+
+    #[test]
+    fn forward_many_agrees_with_forward_for_every_gate_size() {
+        forward_many_agrees_with_forward::<2, 4>(100);
+        forward_many_agrees_with_forward::<4, 16>(400);
+        forward_many_agrees_with_forward::<6, 64>(100);
+    }
+}
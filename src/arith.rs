@@ -0,0 +1,205 @@
+//! Adaptive binary arithmetic coding (LZMA's range coder) of program outputs, so the skew a program puts into its output can
+//! actually be cashed in. Each bit is coded with a probability learnt from the bits that followed the same `order` preceding bits so
+//! far, the context starting out as all zeros at the start of each block. The model carries on from one block to the next
+
+use bit_vec::BitVec;
+
+/// Probabilities are out of 1 << PROB_BITS
+const PROB_BITS: u32 = 11;
+/// How quickly probabilities adapt, a shift of 5 moves them 1/32 of the way to each new bit
+const ADAPT_SHIFT: u32 = 5;
+const TOP: u32 = 1 << 24;
+
+/// The probability of a 0 in each context
+fn initial_probs(order: usize) -> Vec<u16> {
+    vec![1 << (PROB_BITS - 1); 1 << order]
+}
+
+fn update(prob: &mut u16, bit: bool) {
+    if bit {
+        *prob -= *prob >> ADAPT_SHIFT;
+    } else {
+        *prob += ((1 << PROB_BITS) - *prob) >> ADAPT_SHIFT;
+    }
+}
+
+pub struct Encoder {
+    order: usize,
+    probs: Vec<u16>,
+    low: u64,
+    range: u32,
+    /// The last byte out, held back in case a carry still has to be added to it, followed by cache_size - 1 0xFF bytes
+    cache: u8,
+    cache_size: u64,
+    out: Vec<u8>
+}
+
+impl Encoder {
+    pub fn new(order: usize) -> Self {
+        Self { order, probs: initial_probs(order), low: 0, range: u32::MAX, cache: 0, cache_size: 1, out: vec![] }
+    }
+
+    /// Codes one block, its context starting afresh but keeping everything learnt from earlier blocks
+    pub fn encode(&mut self, bits: &BitVec) {
+        let mask = (1 << self.order) - 1;
+        let mut context = 0;
+        for bit in bits {
+            self.encode_bit(context, bit);
+            context = ((context << 1) | bit as usize) & mask;
+        }
+    }
+
+    fn encode_bit(&mut self, context: usize, bit: bool) {
+        let prob = &mut self.probs[context];
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        if bit {
+            self.low += bound as u64;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        update(prob, bit);
+        while self.range < TOP {
+            self.range <<= 8;
+            self.shift_low();
+        }
+    }
+
+    fn shift_low(&mut self) {
+        if (self.low as u32) < 0xFF000000 || self.low >> 32 != 0 {
+            let carry = (self.low >> 32) as u8;
+            let mut byte = self.cache;
+            for _ in 0..self.cache_size {
+                self.out.push(byte.wrapping_add(carry));
+                byte = 0xFF;
+            }
+            self.cache_size = 0;
+            self.cache = (self.low >> 24) as u8;
+        }
+        self.cache_size += 1;
+        self.low = (self.low & 0x00FFFFFF) << 8;
+    }
+
+    /// Flushes the coder, returning everything coded. The first byte out is always zero and the decoder reads zeros past the end
+    /// of its input, so neither the first byte nor any trailing zeros need keeping
+    pub fn finish(mut self) -> Vec<u8> {
+        for _ in 0..5 {
+            self.shift_low();
+        }
+        debug_assert_eq!(self.out[0], 0);
+        self.out.remove(0);
+        let len = self.out.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+        self.out.truncate(len);
+        self.out
+    }
+}
+
+pub struct Decoder<'a> {
+    order: usize,
+    probs: Vec<u16>,
+    code: u32,
+    range: u32,
+    input: &'a [u8]
+}
+
+impl<'a> Decoder<'a> {
+    /// Has to be given the same order it was encoded with, there's nothing in the bytes to say what it was
+    pub fn new(input: &'a [u8], order: usize) -> Self {
+        let mut decoder = Self { order, probs: initial_probs(order), code: 0, range: u32::MAX, input };
+        for _ in 0..4 {
+            decoder.code = (decoder.code << 8) | decoder.next_byte() as u32;
+        }
+        decoder
+    }
+
+    fn next_byte(&mut self) -> u8 {
+        let Some((&byte, rest)) = self.input.split_first() else { return 0 };
+        self.input = rest;
+        byte
+    }
+
+    /// Decodes the next block, given how long it was. Garbage in gets garbage out, so whatever's decoded needs checking some other way
+    pub fn decode(&mut self, len: usize) -> BitVec {
+        let mask = (1 << self.order) - 1;
+        let mut context = 0;
+        (0..len).map(|_| {
+            let bit = self.decode_bit(context);
+            context = ((context << 1) | bit as usize) & mask;
+            bit
+        }).collect()
+    }
+
+    fn decode_bit(&mut self, context: usize) -> bool {
+        let prob = &mut self.probs[context];
+        let bound = (self.range >> PROB_BITS) * *prob as u32;
+        let bit = self.code >= bound;
+        if bit {
+            self.code -= bound;
+            self.range -= bound;
+        } else {
+            self.range = bound;
+        }
+        update(prob, bit);
+        while self.range < TOP {
+            self.range <<= 8;
+            self.code = (self.code << 8) | self.next_byte() as u32;
+        }
+        bit
+    }
+}
+
+/// Codes each block in turn with one encoder, returning the coded bytes
+pub fn encode_all<'a>(blocks: impl IntoIterator<Item = &'a BitVec>, order: usize) -> Vec<u8> {
+    let mut encoder = Encoder::new(order);
+    for block in blocks {
+        encoder.encode(block);
+    }
+    encoder.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::fitness::Entropy;
+
+    fn decoded(coded: &[u8], order: usize, lens: impl IntoIterator<Item = usize>) -> Vec<BitVec> {
+        let mut decoder = Decoder::new(coded, order);
+        lens.into_iter().map(|len| decoder.decode(len)).collect()
+    }
+
+    /// Skewed random blocks come back out, in not much more than the entropy says they can be coded in
+    #[test]
+    fn roundtrips_near_the_entropy() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let order = rng.random_range(0..=8);
+            let p_one = rng.random_range(0.0..1.0);
+            let inputs: Vec<BitVec> = (0..rng.random_range(0..=16)).map(|_| (0..rng.random_range(0..=400)).map(|_| rng.random_bool(p_one)).collect()).collect();
+            let coded = encode_all(&inputs, order);
+            assert_eq!(decoded(&coded, order, inputs.iter().map(BitVec::len)), inputs);
+            // Adapting costs a little, and the bits are independent so longer contexts can only cost more learning
+            let limit = Entropy { order: 0 }.bits(&inputs) * 1.1 + 40.0 * (1 << order) as f64 + 64.0;
+            assert!(((coded.len() * 8) as f64) < limit, "Coded into {} bytes, expected under {limit} bits", coded.len());
+        }
+    }
+
+    /// Every bit being the flip of the one before costs next to nothing once there's a bit of context to learn it from
+    #[test]
+    fn learns_from_the_context() {
+        let alternating: BitVec = (0..4000).map(|i| i % 2 == 1).collect();
+        let (blind, order_1) = (encode_all([&alternating], 0), encode_all([&alternating], 1));
+        assert!(order_1.len() < 40 && blind.len() > 400, "Coded into {} bytes blind and {} with context", blind.len(), order_1.len());
+        assert_eq!(decoded(&order_1, 1, [4000]), [alternating]);
+    }
+
+    #[test]
+    fn codes_empty_blocks() {
+        let empty = BitVec::new();
+        let coded = encode_all([&empty, &empty], 3);
+        assert_eq!(decoded(&coded, 3, [0, 0]), [empty.clone(), empty]);
+        assert_eq!(decoded(&encode_all([], 0), 0, []), Vec::<BitVec>::new());
+    }
+}
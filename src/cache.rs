@@ -0,0 +1,114 @@
+//! Memo of what programs have scored, so the elites (scored again every generation) and the exact clones mutation makes whenever it
+//! happens to change nothing are only ever evaluated once
+
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+use crate::circuit::ReversibleCircuit;
+use crate::pareto::Objectives;
+
+/// Hash of anything, for ids of datasets and fitnesses
+pub fn id_of(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// What a score depends on: the program, what it was run on and how it was scored
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub program: u64,
+    pub dataset: u64,
+    pub fitness: u64
+}
+
+impl Key {
+    pub fn new(program: &impl ReversibleCircuit, dataset: u64, fitness: u64) -> Self {
+        Self { program: program.structural_hash(), dataset, fitness }
+    }
+}
+
+/// Holds at most `capacity` scores, dropping the least recently used half whenever it fills up
+#[derive(Debug)]
+pub struct FitnessCache {
+    capacity: usize,
+    /// Each score along with when it was last looked up
+    entries: HashMap<Key, (Objectives, u64)>,
+    clock: u64,
+    pub hits: u64,
+    pub lookups: u64
+}
+
+impl FitnessCache {
+    /// A capacity of 0 never keeps anything
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, entries: HashMap::new(), clock: 0, hits: 0, lookups: 0 }
+    }
+
+    pub fn get(&mut self, key: &Key) -> Option<Objectives> {
+        self.clock += 1;
+        self.lookups += 1;
+        let (objectives, used) = self.entries.get_mut(key)?;
+        *used = self.clock;
+        self.hits += 1;
+        Some(*objectives)
+    }
+
+    pub fn insert(&mut self, key: Key, objectives: Objectives) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.entries.len() >= self.capacity {
+            // Dropping half at a time rather than one entry per insert keeps the sorting cheap
+            let mut used = self.entries.values().map(|&(_, used)| used).collect::<Vec<_>>();
+            let (_, &mut cutoff, _) = used.select_nth_unstable(self.entries.len() / 2);
+            self.entries.retain(|_, &mut (_, used)| used > cutoff);
+        }
+        self.clock += 1;
+        self.entries.insert(key, (objectives, self.clock));
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(program: u64) -> Key {
+        Key { program, dataset: 1, fitness: 2 }
+    }
+
+    fn objectives(saved: i64) -> Objectives {
+        Objectives { saved, gates: 3, variance: 0.0 }
+    }
+
+    #[test]
+    fn gives_back_what_it_was_given() {
+        let mut cache = FitnessCache::new(16);
+        cache.insert(key(1), objectives(5));
+        assert_eq!(cache.get(&key(1)), Some(objectives(5)));
+        assert_eq!(cache.get(&Key { dataset: 3, ..key(1) }), None);
+        assert_eq!(cache.get(&Key { fitness: 3, ..key(1) }), None);
+        assert_eq!(cache.get(&key(2)), None);
+        assert_eq!((cache.hits, cache.lookups), (1, 4));
+        let mut off = FitnessCache::new(0);
+        off.insert(key(1), objectives(5));
+        assert_eq!((off.len(), off.get(&key(1))), (0, None));
+    }
+
+    /// Filling up drops the half that was used longest ago, so whatever keeps being looked up stays
+    #[test]
+    fn drops_the_least_recently_used() {
+        let mut cache = FitnessCache::new(8);
+        for program in 0..100 {
+            cache.insert(key(program), objectives(program as i64));
+            assert!(cache.len() <= 8, "Cache grew to {} over its capacity", cache.len());
+            assert_eq!(cache.get(&key(0)), Some(objectives(0)), "Lost the entry used every time, at {program}");
+        }
+        assert_eq!(cache.get(&key(1)), None);
+        assert_eq!(cache.get(&key(99)), Some(objectives(99)));
+    }
+}
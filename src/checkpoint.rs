@@ -0,0 +1,185 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::container::crc32;
+use crate::serialization::ByteReader;
+
+const MAGIC: [u8; 4] = *b"RVCK";
+const VERSION: u8 = 2;
+/// How many of the most recent checkpoints to leave on disk, older ones get deleted as new ones are written
+const KEEP: usize = 3;
+
+/// Everything needed to carry on a search from the start of `generation`.
+/// On disk: MAGIC, VERSION, generation, seed, history length then each score (i64), population size then each program
+/// as its length then its serialized bytes, then the number of islands then each one's size (all numbers u64 LE), then a CRC-32 of
+/// the lot. Version 1 had no islands, which reads as a single island of the whole population
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub generation: u64,
+    /// The master seed of the run, every individual's rng is derived from this so none of them need saving
+    pub seed: u64,
+    /// Best score of every generation so far
+    pub history: Vec<i64>,
+    /// Serialized programs, each island's in turn, best first
+    pub population: Vec<Vec<u8>>,
+    /// How many of the population are on each island
+    pub islands: Vec<u64>
+}
+
+impl Checkpoint {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = MAGIC.to_vec();
+        out.push(VERSION);
+        for n in [self.generation, self.seed, self.history.len() as u64] {
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+        self.history.iter().for_each(|score| out.extend_from_slice(&score.to_le_bytes()));
+        out.extend_from_slice(&(self.population.len() as u64).to_le_bytes());
+        for program in &self.population {
+            out.extend_from_slice(&(program.len() as u64).to_le_bytes());
+            out.extend_from_slice(program);
+        }
+        out.extend_from_slice(&(self.islands.len() as u64).to_le_bytes());
+        self.islands.iter().for_each(|size| out.extend_from_slice(&size.to_le_bytes()));
+        let checksum = crc32(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if bytes.len() < 4 || bytes[..4] != MAGIC {
+            return Err(invalid("Not a checkpoint".to_string()));
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(body).to_le_bytes() != checksum {
+            return Err(invalid("Checkpoint checksum mismatch".to_string()));
+        }
+
+        let mut reader = ByteReader::new(&body[4..]);
+        let version = reader.u8()?;
+        if version != VERSION && version != 1 {
+            return Err(invalid(format!("Unsupported checkpoint version {version} (expected {VERSION})")));
+        }
+        let generation = reader.u64()?;
+        let seed = reader.u64()?;
+        let history = (0..reader.u64()?).map(|_| reader.u64().map(|s| s as i64)).collect::<Result<_, _>>()?;
+        let population = (0..reader.u64()?).map(|_| {
+            let len = reader.u64()? as usize;
+            reader.take(len).map(<[u8]>::to_vec)
+        }).collect::<Result<Vec<_>, _>>()?;
+        let islands = if version == 1 {
+            vec![population.len() as u64]
+        } else {
+            (0..reader.u64()?).map(|_| reader.u64()).collect::<Result<Vec<_>, _>>()?
+        };
+        if islands.iter().sum::<u64>() != population.len() as u64 {
+            return Err(invalid(format!("Checkpoint's islands add up to {} programs rather than {}", islands.iter().sum::<u64>(), population.len())));
+        }
+        reader.finish()?;
+        Ok(Self { generation, seed, history, population, islands })
+    }
+}
+
+fn path_for(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("checkpoint-{generation:010}.ckpt"))
+}
+
+/// Writes to a temporary file then renames it into place, so a kill mid-write never leaves a half written checkpoint behind
+pub fn save(dir: &Path, checkpoint: &Checkpoint) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(".checkpoint.tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&checkpoint.to_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp, path_for(dir, checkpoint.generation))?;
+
+    let old = list(dir)?;
+    for (_, path) in &old[..old.len().saturating_sub(KEEP)] {
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+/// Loads the checkpoint of the furthest generation in `dir`, if there are any
+pub fn load_latest(dir: &Path) -> io::Result<Option<Checkpoint>> {
+    match list(dir)?.last() {
+        Some((_, path)) => Checkpoint::from_bytes(&fs::read(path)?)
+            .map(Some)
+            .map_err(|e| io::Error::new(e.kind(), format!("{}: {e}", path.display()))),
+        None => Ok(None)
+    }
+}
+
+/// Checkpoints in `dir` sorted by generation
+fn list(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut found = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let generation = path.file_name().and_then(|n| n.to_str())
+            .and_then(|n| n.strip_prefix("checkpoint-")?.strip_suffix(".ckpt")?.parse().ok());
+        if let Some(generation) = generation {
+            found.push((generation, path));
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checkpoint(generation: u64) -> Checkpoint {
+        Checkpoint {
+            generation,
+            seed: 0x5eed,
+            history: vec![-3, 0, 7, i64::MAX],
+            population: vec![vec![1, 2, 3], vec![], vec![0xff; 40]],
+            islands: vec![2, 1]
+        }
+    }
+
+    /// Checkpoints have to reload exactly, and any damage to them has to be noticed rather than resuming from garbage
+    #[test]
+    fn roundtrips_and_refuses_damage() {
+        let bytes = checkpoint(12).to_bytes();
+        assert_eq!(Checkpoint::from_bytes(&bytes).unwrap(), checkpoint(12));
+        for i in 0..bytes.len() {
+            let mut corrupted = bytes.clone();
+            corrupted[i] ^= 0x10;
+            assert!(Checkpoint::from_bytes(&corrupted).is_err(), "Flipping a bit of byte {i} wasn't noticed");
+            assert!(Checkpoint::from_bytes(&bytes[..i]).is_err(), "Truncating to {i} bytes wasn't noticed");
+        }
+    }
+
+    /// Version 1 had no islands, so it reads as one island of everything
+    #[test]
+    fn reads_version_1_as_one_island() {
+        let bytes = checkpoint(12).to_bytes();
+        // Less the checksum and the island count and sizes before it
+        let mut v1 = bytes[..bytes.len() - 4 - 3 * 8].to_vec();
+        v1[4] = 1;
+        let checksum = crc32(&v1);
+        v1.extend_from_slice(&checksum.to_le_bytes());
+        assert_eq!(Checkpoint::from_bytes(&v1).unwrap(), Checkpoint { islands: vec![3], ..checkpoint(12) });
+        let uneven = Checkpoint { islands: vec![2, 2], ..checkpoint(12) };
+        assert!(Checkpoint::from_bytes(&uneven.to_bytes()).is_err(), "Islands that don't add up were accepted");
+    }
+
+    #[test]
+    fn keeps_the_latest_few() {
+        let dir = std::env::temp_dir().join(format!("checkpoint-test-{}", std::process::id()));
+        assert!(load_latest(&dir).unwrap().is_none());
+        for generation in [5, 10, 15, 20, 25] {
+            save(&dir, &checkpoint(generation)).unwrap();
+        }
+        assert_eq!(list(&dir).unwrap().iter().map(|(generation, _)| *generation).collect::<Vec<_>>(), [15, 20, 25]);
+        assert_eq!(load_latest(&dir).unwrap(), Some(checkpoint(25)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,290 @@
+use std::{fmt, fs, io};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::ops::Range;
+use std::path::Path;
+
+use bit_vec::BitVec;
+use rand::Rng;
+
+use crate::crossover::Crossover;
+use crate::serialization::{self, FormatError};
+
+/// Largest inp_size `verify` will try every input of
+pub const EXHAUSTIVE_LIMIT: usize = 20;
+
+/// Transposes a 64x64 bit matrix in place, bit j of rows[i] swapping with bit i of rows[j], by swapping ever smaller blocks
+fn transpose(rows: &mut [u64; 64]) {
+    let mut j = 32;
+    let mut mask = 0x0000_0000_FFFF_FFFFu64;
+    while j != 0 {
+        for k in (0..64).filter(|k| k & j == 0) {
+            let t = ((rows[k] >> j) ^ rows[k | j]) & mask;
+            rows[k | j] ^= t;
+            rows[k] ^= t << j;
+        }
+        j >>= 1;
+        mask ^= mask << j;
+    }
+}
+
+/// Inputs packed for `forward_sliced`, a chunk of inp_size words for every 64 of them (the last chunk maybe having unused lanes).
+/// Packing and unpacking go 64 wires at a time by transposing whole words, as going a bit at a time costs more than the gates
+/// themselves on small programs
+#[derive(Clone, Debug)]
+pub struct Sliced {
+    words: Vec<u64>,
+    inp_size: usize,
+    len: usize
+}
+
+impl Sliced {
+    /// Pads the inputs up to inp_size the way `C::forward` would
+    pub fn pack<C: ReversibleCircuit>(inputs: &[BitVec], inp_size: usize) -> Self {
+        let groups = inp_size.div_ceil(64);
+        let padding = (0..groups).map(|g| (0..64).filter(|b| g * 64 + b < inp_size && C::padding(g * 64 + b)).fold(0u64, |w, b| w | 1 << b)).collect::<Vec<_>>();
+        let mut words = Vec::with_capacity(inputs.len().div_ceil(64) * inp_size);
+        for chunk in inputs.chunks(64) {
+            for (g, &padding) in padding.iter().enumerate() {
+                let mut rows = [0u64; 64];
+                for (row, input) in rows.iter_mut().zip(chunk) {
+                    assert!(input.len() <= inp_size);
+                    // BitVec keeps bit i at bit i % 32 of its i / 32'th u32
+                    let blocks = input.storage();
+                    let bits = blocks.get(2 * g).map_or(0, |&b| b as u64) | blocks.get(2 * g + 1).map_or(0, |&b| (b as u64) << 32);
+                    let real = input.len().saturating_sub(g * 64).min(64);
+                    let real = if real == 64 { u64::MAX } else { (1 << real) - 1 };
+                    *row = bits & real | padding & !real;
+                }
+                transpose(&mut rows);
+                words.extend_from_slice(&rows[..(inp_size - g * 64).min(64)]);
+            }
+        }
+        Self { words, inp_size, len: inputs.len() }
+    }
+
+    /// Every input's full inp_size wires
+    pub fn unpack(&self) -> Vec<BitVec> {
+        let mut outputs = Vec::with_capacity(self.len);
+        for (c, wires) in self.words.chunks(self.inp_size).enumerate() {
+            let mut bytes = vec![Vec::with_capacity(self.inp_size.div_ceil(64) * 8); (self.len - c * 64).min(64)];
+            for words in wires.chunks(64) {
+                let mut rows = [0u64; 64];
+                rows[..words.len()].copy_from_slice(words);
+                transpose(&mut rows);
+                // from_bytes wants the first bit as the top of the first byte
+                bytes.iter_mut().zip(rows).for_each(|(b, row)| b.extend(row.reverse_bits().to_be_bytes()));
+            }
+            outputs.extend(bytes.into_iter().map(|b| {
+                let mut output = BitVec::from_bytes(&b);
+                output.truncate(self.inp_size);
+                output
+            }));
+        }
+        outputs
+    }
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    /// A gate's wires repeat or go past inp_size, or its SBox isn't a permutation
+    BadGate(FormatError),
+    WrongLength { input: BitVec, output_len: usize },
+    /// Two inputs that `forward` sends to the same output
+    Collision { first: BitVec, second: BitVec },
+    /// `backward` didn't undo `forward` on this input
+    NotInverted { input: BitVec }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::BadGate(e) => write!(f, "{e}"),
+            VerifyError::WrongLength { input, output_len } => write!(f, "forward({input}) gave {output_len} bits rather than {}", input.len()),
+            VerifyError::Collision { first, second } => write!(f, "forward({first}) == forward({second})"),
+            VerifyError::NotInverted { input } => write!(f, "backward(forward({input})) isn't {input}")
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+impl From<FormatError> for VerifyError {
+    fn from(e: FormatError) -> Self {
+        VerifyError::BadGate(e)
+    }
+}
+
+/// What the search, codec and streaming need from a program, so they don't care which kind of gates it's built from
+pub trait ReversibleCircuit: Clone + Send + Sync + Sized + Hash {
+    /// The simplest program there is, to start a search from
+    fn new(inp_size: usize) -> Self;
+    /// How many wires each gate uses, so the least inp_size a program can have
+    #[cfg(test)]
+    const GATE_WIRES: usize;
+    /// A program of `gates` random gates, for the tests
+    #[cfg(test)]
+    fn random(inp_size: usize, gates: usize, rng: &mut impl Rng) -> Self;
+    /// Pads `input` up to inp_size and runs the gates over it
+    fn forward(&self, input: BitVec) -> BitVec;
+    /// Undoes `forward`, given its full inp_size output
+    fn backward(&self, output: BitVec) -> BitVec;
+    /// What `forward` pads an input with on `wire`, if the input doesn't reach it
+    fn padding(wire: usize) -> bool;
+    /// Runs just `gates` of the gates on 64 inputs at once, lane j of each wire's word being the j'th input's bit on that wire (padding included)
+    fn forward_sliced(&self, wires: &mut [u64], gates: Range<usize>);
+    fn gate_count(&self) -> usize;
+    /// How many gates at the start of the two programs are the same, so running either gets the same wires up to there
+    fn shared_prefix(&self, other: &Self) -> usize;
+
+    /// `forward_sliced` on every chunk of `inputs`
+    fn forward_gates(&self, inputs: &mut Sliced, gates: Range<usize>) {
+        inputs.words.chunks_mut(self.inp_size()).for_each(|wires| self.forward_sliced(wires, gates.clone()));
+    }
+
+    /// `forward` on every input, 64 at a time through `forward_sliced`
+    fn forward_many(&self, inputs: &[BitVec]) -> Vec<BitVec> {
+        let mut sliced = Sliced::pack::<Self>(inputs, self.inp_size());
+        self.forward_gates(&mut sliced, 0..self.gate_count());
+        sliced.unpack()
+    }
+    fn mutate(&self, mut_rate: f64, rng: &mut impl Rng) -> Self;
+    /// Child made of gates from both parents, which have to share an inp_size
+    fn crossover(&self, other: &Self, crossover: Crossover, rng: &mut impl Rng) -> Self;
+    /// Size of the program, which is what the search penalises
+    fn complexity(&self) -> i64;
+    fn inp_size(&self) -> usize;
+
+    /// Checks the gates one at a time, which is enough to know the program is reversible if the gates are implemented right
+    fn verify_gates(&self) -> Result<(), VerifyError>;
+
+    /// `verify_gates`, then if inp_size is at most EXHAUSTIVE_LIMIT runs every possible input through `forward` and `backward`,
+    /// returning whether it did the latter
+    fn verify(&self) -> Result<bool, VerifyError> {
+        self.verify_gates()?;
+        let n = self.inp_size();
+        if n > EXHAUSTIVE_LIMIT {
+            return Ok(false);
+        }
+        let to_bits = |x: usize| (0..n).map(|i| x >> i & 1 == 1).collect::<BitVec>();
+        // Which input (plus one) each output came from, since any collision means some output isn't reachable
+        let mut seen = vec![0u32; 1 << n];
+        for x in 0..1 << n {
+            let input = to_bits(x);
+            let output = self.forward(input.clone());
+            if output.len() != n {
+                return Err(VerifyError::WrongLength { input, output_len: output.len() });
+            }
+            let index = output.iter().enumerate().map(|(i, b)| (b as usize) << i).sum::<usize>();
+            if seen[index] != 0 {
+                return Err(VerifyError::Collision { first: to_bits(seen[index] as usize - 1), second: input });
+            }
+            seen[index] = x as u32 + 1;
+            if self.backward(output) != input {
+                return Err(VerifyError::NotInverted { input });
+            }
+        }
+        Ok(true)
+    }
+
+    /// Compact binary form, see `serialization` for the layout
+    fn to_bytes(&self) -> Vec<u8>;
+    fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError>;
+    /// Human readable form, see `serialization` for the layout
+    fn to_text(&self) -> String;
+    fn from_text(text: &str) -> Result<Self, FormatError>;
+
+    /// Hash of the gates and inp_size, so programs that are the same have the same hash however they were bred
+    fn structural_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// Saved as text unless the file is named *.bin
+    fn save(&self, path: &Path) -> io::Result<()> {
+        if path.extension().is_some_and(|e| e == "bin") {
+            fs::write(path, self.to_bytes())
+        } else {
+            fs::write(path, self.to_text())
+        }
+    }
+
+    /// Loads either form, working out which from the contents
+    fn load(path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let res = if bytes.starts_with(&serialization::MAGIC) {
+            Self::from_bytes(&bytes)
+        } else {
+            Self::from_text(&String::from_utf8_lossy(&bytes))
+        };
+        res.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {e}", path.display())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::{arbitrairy_program, fredkins_program};
+
+    #[test]
+    fn saves_as_text_unless_named_bin() {
+        let dir = std::env::temp_dir().join(format!("circuit-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut rng = StdRng::seed_from_u64(1);
+        let program = (0..20).fold(arbitrairy_program::Program::<4, 16>::new(64), |p, _| p.mutate(0.3, &mut rng));
+        for name in ["program.txt", "program.bin"] {
+            let path = dir.join(name);
+            program.save(&path).unwrap();
+            assert_eq!(fs::read(&path).unwrap().starts_with(&serialization::MAGIC), name.ends_with(".bin"));
+            assert_eq!(arbitrairy_program::Program::<4, 16>::load(&path).unwrap().to_bytes(), program.to_bytes());
+            let err = fredkins_program::Program::load(&path).err().unwrap();
+            assert!(err.to_string().contains(name), "Error doesn't say which file: {err}");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn transposes_bit_matrices() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let rows: [u64; 64] = std::array::from_fn(|_| rng.random());
+        let mut transposed = rows;
+        transpose(&mut transposed);
+        for (i, row) in transposed.iter().enumerate() {
+            assert!((0..64).all(|j| row >> j & 1 == rows[j] >> i & 1), "Row {i} isn't column {i}");
+        }
+        transpose(&mut transposed);
+        assert_eq!(transposed, rows);
+    }
+
+    /// Unpacking gives back every input padded the way `forward` pads it, even when the last 64 aren't all there
+    #[test]
+    fn sliced_inputs_come_back_padded() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for count in [0, 1, 64, 100] {
+            let inputs: Vec<BitVec> = (0..count).map(|_| (0..rng.random_range(0..=130)).map(|_| rng.random_bool(0.5)).collect()).collect();
+            let padded = inputs.iter().map(|i| i.iter().chain((i.len()..130).map(|wire| wire % 2 == 0)).collect::<BitVec>()).collect::<Vec<_>>();
+            assert!(Sliced::pack::<fredkins_program::Program>(&inputs, 130).unpack() == padded);
+        }
+    }
+
+    /// However a program came about, the same gates have to hash the same, and (almost always) different ones differently
+    #[test]
+    fn hashes_by_structure() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut p = arbitrairy_program::Program::<4, 16>::random(50, 10, &mut rng);
+        for _ in 0..200 {
+            assert_eq!(p.clone().structural_hash(), p.structural_hash());
+            assert_eq!(arbitrairy_program::Program::<4, 16>::from_bytes(&p.to_bytes()).unwrap().structural_hash(), p.structural_hash());
+            let child = p.mutate(0.3, &mut rng);
+            if child.to_bytes() != p.to_bytes() {
+                assert_ne!(child.structural_hash(), p.structural_hash(), "Different programs hashed the same");
+            }
+            p = child;
+        }
+        let fredkins = fredkins_program::Program::new(50);
+        assert_ne!(fredkins.structural_hash(), fredkins_program::Program::new(51).structural_hash());
+    }
+}
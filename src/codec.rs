@@ -0,0 +1,39 @@
+use bit_vec::BitVec;
+use itertools::repeat_n;
+
+use crate::circuit::ReversibleCircuit;
+use crate::container;
+
+/// Identifies the program a file was compressed with, so decompressing with the wrong one is caught rather than producing garbage
+pub fn program_hash(program: &impl ReversibleCircuit) -> u64 {
+    container::fnv1a(&program.to_bytes())
+}
+
+/// Lengths of the zero runs at the start and end of `res`, which don't overlap even if it's all zeros
+pub fn zero_runs(res: &BitVec) -> (usize, usize) {
+    let leading = res.iter().take_while(|b| !b).count();
+    let trailing = res.iter().skip(leading).rev().take_while(|b| !b).count();
+    (leading, trailing)
+}
+
+pub fn strip(res: &BitVec, leading: usize, trailing: usize) -> BitVec {
+    res.iter().skip(leading).take(res.len() - leading - trailing).collect()
+}
+
+pub fn unstrip(leading: usize, payload: &BitVec, trailing: usize) -> BitVec {
+    repeat_n(false, leading).chain(payload.iter()).chain(repeat_n(false, trailing)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn zero_runs_never_overlap() {
+        assert_eq!(zero_runs(&BitVec::from_elem(10, false)), (10, 0));
+        assert_eq!(zero_runs(&BitVec::from_bytes(&[0b0001_0100])), (3, 2));
+        let res = BitVec::from_bytes(&[0b0011_0000, 0b1000_0000]);
+        let (leading, trailing) = zero_runs(&res);
+        assert_eq!(unstrip(leading, &strip(&res, leading, trailing), trailing), res);
+    }
+}
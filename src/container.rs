@@ -0,0 +1,91 @@
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum ContainerError {
+    Io(io::Error),
+    BadMagic([u8; 4]),
+    UnsupportedVersion(u8),
+    /// The zero runs and payload don't add up to inp_size, or the original is longer than inp_size
+    InconsistentLengths { original_bits: u64, inp_size: u64, leading_zeros: u64, trailing_zeros: u64, payload_bits: u64 },
+    /// The bits used to pad the payload out to a whole byte weren't zero
+    NonZeroPadding,
+    ChecksumMismatch { stored: u32, computed: u32 },
+    /// There was more data after the checksum
+    TrailingData,
+    ProgramMismatch { expected: u64, found: u64 },
+    /// A stream had a block shorter than inp_size that wasn't its last
+    ShortBlockNotLast,
+    /// A stream's payload byte named no known way of coding blocks
+    UnsupportedPayload(u8)
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::Io(e) => write!(f, "I/O error reading compressed file: {e}"),
+            ContainerError::BadMagic(magic) => write!(f, "Not a compressed file (magic was {magic:?})"),
+            ContainerError::UnsupportedVersion(v) => write!(f, "Unsupported format version {v}"),
+            ContainerError::InconsistentLengths { original_bits, inp_size, leading_zeros, trailing_zeros, payload_bits } =>
+                write!(f, "Inconsistent lengths: {original_bits} original bits, inp_size {inp_size}, {leading_zeros} leading + {trailing_zeros} trailing zeros + {payload_bits} payload bits"),
+            ContainerError::NonZeroPadding => write!(f, "Payload padding bits aren't zero"),
+            ContainerError::ChecksumMismatch { stored, computed } => write!(f, "Checksum mismatch: stored {stored:08x}, computed {computed:08x}"),
+            ContainerError::TrailingData => write!(f, "Unexpected data after the checksum"),
+            ContainerError::ProgramMismatch { expected, found } => write!(f, "Compressed with program {expected:016x} but given program {found:016x}"),
+            ContainerError::ShortBlockNotLast => write!(f, "A block other than the last is shorter than inp_size"),
+            ContainerError::UnsupportedPayload(n) => write!(f, "Unsupported payload coding {n}")
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+impl From<io::Error> for ContainerError {
+    fn from(e: io::Error) -> Self {
+        ContainerError::Io(e)
+    }
+}
+
+impl From<ContainerError> for io::Error {
+    fn from(e: ContainerError) -> Self {
+        match e {
+            ContainerError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e)
+        }
+    }
+}
+
+/// Standard CRC-32 (as in zip/png), done bit by bit since it's only run once per file
+pub fn crc32(bytes: &[u8]) -> u32 {
+    crc32_continue(0, bytes)
+}
+
+/// Carries on a CRC-32 from an earlier result, so that `crc32_continue(crc32(a), b) == crc32(a ++ b)`
+pub fn crc32_continue(crc: u32, bytes: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// 64 bit FNV-1a, used to fingerprint programs since std's hashers aren't guaranteed to be stable across releases
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x100000001b3))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_match_the_standard_ones() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_eq!(crc32_continue(crc32(b"1234"), b"56789"), crc32(b"123456789"));
+    }
+}
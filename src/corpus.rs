@@ -0,0 +1,94 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use bit_vec::BitVec;
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+
+/// Training data, already cut into blocks the program can take
+#[derive(Clone, Debug, Default)]
+pub struct Corpus {
+    pub train: Vec<BitVec>,
+    /// Held back from the search, so we can see whether what it finds generalises
+    pub validation: Vec<BitVec>
+}
+
+impl Corpus {
+    /// Reads every file in `paths` (recursing into directories, in sorted order so the blocks come out the same every time)
+    /// and cuts each into `block_bits` long blocks, the last block of a file being whatever's left over.
+    /// `validation_fraction` of the blocks, picked at random by `seed`, go to validation
+    pub fn load(paths: &[PathBuf], block_bits: usize, validation_fraction: f64, seed: u64) -> io::Result<Self> {
+        assert!(block_bits > 0);
+        let mut files = vec![];
+        for path in paths {
+            collect_files(path, &mut files)?;
+        }
+        let mut blocks = vec![];
+        for file in &files {
+            let bytes = fs::read(file)?;
+            if block_bits.is_multiple_of(8) {
+                blocks.extend(bytes.chunks(block_bits / 8).map(BitVec::from_bytes));
+            } else {
+                blocks.extend(BitVec::from_bytes(&bytes).iter().chunks(block_bits).into_iter().map(|block| block.collect::<BitVec>()));
+            }
+        }
+        if blocks.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("No data found in {paths:?}")));
+        }
+
+        blocks.shuffle(&mut StdRng::seed_from_u64(seed));
+        let validation = blocks.split_off(blocks.len() - (blocks.len() as f64 * validation_fraction).round() as usize);
+        if blocks.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "validation_fraction leaves no blocks to train on"));
+        }
+        Ok(Self { train: blocks, validation })
+    }
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    if path.is_dir() {
+        let mut entries = fs::read_dir(path)?.map(|e| e.map(|e| e.path())).collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            collect_files(&entry, files)?;
+        }
+    } else {
+        files.push(path.to_path_buf());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use super::*;
+
+    #[test]
+    fn cuts_files_into_blocks() {
+        let dir = std::env::temp_dir().join(format!("corpus-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let (a, b) = ((0..=255).collect::<Vec<u8>>(), vec![0xA5; 7]);
+        fs::write(dir.join("a"), &a).unwrap();
+        fs::write(dir.join("sub").join("b"), &b).unwrap();
+        for block_bits in [1, 13, 64, 400] {
+            let corpus = Corpus::load(std::slice::from_ref(&dir), block_bits, 0.0, 0).unwrap();
+            let mut expected = [&a, &b].map(|file| BitVec::from_bytes(file).iter().chunks(block_bits).into_iter().map(|c| c.collect::<BitVec>()).collect_vec()).concat();
+            let mut blocks = corpus.train;
+            // Shuffled, but only ever the same blocks
+            blocks.sort_by_key(|block| (block.to_bytes(), block.len()));
+            expected.sort_by_key(|block| (block.to_bytes(), block.len()));
+            assert!(blocks == expected, "Blocks of {block_bits} bits came out wrong");
+        }
+        let split = Corpus::load(std::slice::from_ref(&dir), 8, 0.25, 0).unwrap();
+        assert_eq!((split.train.len(), split.validation.len()), (197, 66));
+        assert!(Corpus::load(std::slice::from_ref(&dir), 8, 0.999, 0).is_err(), "Kept nothing to train on");
+        fs::write(dir.join("a"), []).unwrap();
+        fs::write(dir.join("sub").join("b"), []).unwrap();
+        assert!(Corpus::load(std::slice::from_ref(&dir), 8, 0.0, 0).is_err(), "Loaded a corpus of empty files");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
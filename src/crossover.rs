@@ -0,0 +1,154 @@
+use std::collections::HashSet;
+
+use rand::Rng;
+
+/// How many cuts to make in each parent
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Points {
+    /// Child is the start of one parent followed by the end of the other
+    One,
+    /// Child is one parent with a stretch of it replaced by a stretch of the other
+    Two
+}
+
+/// How a cut in the first parent picks where to cut the second
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Alignment {
+    /// At the same gate index
+    Position,
+    /// Where the gates before the cut touch the most similar set of wires, so that gates doing the same job in both lineages
+    /// line up even after insertions and deletions have shifted them around
+    Wires
+}
+
+impl Points {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "one_point" => Some(Points::One),
+            "two_point" => Some(Points::Two),
+            _ => None
+        }
+    }
+}
+
+impl Alignment {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "position" => Some(Alignment::Position),
+            "wires" => Some(Alignment::Wires),
+            _ => None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crossover {
+    pub points: Points,
+    pub alignment: Alignment
+}
+
+impl Crossover {
+    /// Recombines two gate lists, `wires` giving the wires each gate touches. The child is never empty as long as `a` isn't.
+    /// Gates are kept whole, so as long as both parents' gates were valid for the same inp_size so are the child's
+    pub fn splice<T: Clone, W: IntoIterator<Item = usize>>(&self, a: &[T], b: &[T], wires: impl Fn(&T) -> W, rng: &mut impl Rng) -> Vec<T> {
+        let child: Vec<T> = match self.points {
+            Points::One => {
+                let i = rng.random_range(0..=a.len());
+                let j = self.align(a, i, b, 0, &wires);
+                a[..i].iter().chain(&b[j..]).cloned().collect()
+            }
+            Points::Two => {
+                let (i1, i2) = {
+                    let (x, y) = (rng.random_range(0..=a.len()), rng.random_range(0..=a.len()));
+                    (x.min(y), x.max(y))
+                };
+                let j1 = self.align(a, i1, b, 0, &wires);
+                let j2 = self.align(a, i2, b, j1, &wires);
+                a[..i1].iter().chain(&b[j1..j2]).chain(&a[i2..]).cloned().collect()
+            }
+        };
+        if child.is_empty() { a.to_vec() } else { child }
+    }
+
+    /// Where to cut `b` (no earlier than `from`) to match cutting `a` before gate `i`
+    fn align<T, W: IntoIterator<Item = usize>>(&self, a: &[T], i: usize, b: &[T], from: usize, wires: impl Fn(&T) -> W) -> usize {
+        match self.alignment {
+            Alignment::Position => i.clamp(from, b.len()),
+            Alignment::Wires => {
+                let before: HashSet<usize> = a[..i].iter().flat_map(&wires).collect();
+                // Grow b's prefix a gate at a time, keeping track of how far its wires are from `before` (size of the symmetric difference)
+                let mut seen = HashSet::new();
+                let (mut shared, mut extra) = (0, 0);
+                let mut distances = Vec::with_capacity(b.len() + 1);
+                distances.push(before.len());
+                for gate in b {
+                    for wire in wires(gate) {
+                        if seen.insert(wire) {
+                            if before.contains(&wire) { shared += 1 } else { extra += 1 }
+                        }
+                    }
+                    distances.push(before.len() - shared + extra);
+                }
+                // Ties go to whichever is closest to the same position, which is also what an empty `before` falls back to
+                (from..=b.len()).min_by_key(|&j| (distances[j], j.abs_diff(i))).unwrap()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    const EVERY_WAY: [Crossover; 4] = [
+        Crossover { points: Points::One, alignment: Alignment::Position },
+        Crossover { points: Points::Two, alignment: Alignment::Position },
+        Crossover { points: Points::One, alignment: Alignment::Wires },
+        Crossover { points: Points::Two, alignment: Alignment::Wires }
+    ];
+
+    fn wires(gate: &[usize; 2]) -> [usize; 2] {
+        *gate
+    }
+
+    #[test]
+    fn splicing_with_itself_changes_nothing() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let gates = (0..20).map(|i| [i, (i * 7 + 3) % 20]).collect::<Vec<_>>();
+        for crossover in EVERY_WAY {
+            for _ in 0..20 {
+                assert_eq!(crossover.splice(&gates, &gates, wires, &mut rng), gates, "{crossover:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn child_is_never_empty() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for crossover in EVERY_WAY {
+            for _ in 0..20 {
+                assert!(!crossover.splice(&[[0, 1]], &[], wires, &mut rng).is_empty(), "{crossover:?}");
+            }
+        }
+    }
+
+    /// `b` is `a` with a gate inserted at the front, cutting it in the same place by wires should allow for that
+    #[test]
+    fn wires_alignment_follows_shifted_gates() {
+        let a = [[0, 1], [2, 3], [4, 5]];
+        let b = [[6, 7], [0, 1], [2, 3], [4, 5]];
+        let by = |alignment| Crossover { points: Points::One, alignment }.align(&a, 2, &b, 0, wires);
+        assert_eq!(by(Alignment::Position), 2);
+        assert_eq!(by(Alignment::Wires), 3);
+    }
+
+    #[test]
+    fn found_by_name() {
+        assert_eq!(Points::from_name("two_point"), Some(Points::Two));
+        assert_eq!(Alignment::from_name("wires"), Some(Alignment::Wires));
+        assert_eq!(Points::from_name("three_point"), None);
+    }
+}
@@ -0,0 +1,278 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use bit_vec::BitVec;
+use itertools::Itertools;
+
+use crate::arith;
+use crate::circuit::ReversibleCircuit;
+use crate::stream;
+
+/// Some guess at how many bits a program saves on its inputs, which is what the search maximises (before the complexity penalty)
+pub trait Fitness: Debug + Send + Sync {
+    /// What it's called in configs
+    fn name(&self) -> String;
+    /// Bits saved on one block, `output` being the program's full inp_size output for `input`
+    fn score_block(&self, input: &BitVec, output: &BitVec) -> i64;
+    /// Bits saved on a whole set of blocks, which is just the sum unless the fitness looks at them all together
+    fn score(&self, inputs: &[BitVec], outputs: &[BitVec]) -> i64 {
+        inputs.iter().zip_eq(outputs).map(|(input, output)| self.score_block(input, output)).sum()
+    }
+}
+
+/// Every fitness there is, by the name it's given in configs
+pub fn from_name(name: &str) -> Option<Arc<dyn Fitness>> {
+    Some(match name {
+        "edge_zeros" => Arc::new(EdgeZeros),
+        "run_count" => Arc::new(RunCount),
+        "run_lengths" => Arc::new(RunLengths),
+        "alternating_padding" => Arc::new(AlternatingPadding),
+        "entropy" => Arc::new(Entropy { order: 0 }),
+        "coded" => Arc::new(Coded { order: 0 }),
+        _ => {
+            let (name, order) = name.split_once(':')?;
+            let order = order.parse().ok().filter(|&k| k <= Entropy::MAX_ORDER)?;
+            match name {
+                "entropy" => Arc::new(Entropy { order }),
+                "coded" => Arc::new(Coded { order }),
+                _ => return None
+            }
+        }
+    })
+}
+
+/// Runs the program over every input and scores the lot
+pub fn evaluate(fitness: &dyn Fitness, program: &impl ReversibleCircuit, inputs: &[BitVec]) -> i64 {
+    fitness.score(inputs, &program.forward_many(inputs))
+}
+
+/// Runs of zeros at either end of the output, which is what the codec can actually strip
+#[derive(Debug)]
+pub struct EdgeZeros;
+
+impl Fitness for EdgeZeros {
+    fn name(&self) -> String { "edge_zeros".to_string() }
+
+    fn score_block(&self, input: &BitVec, output: &BitVec) -> i64 {
+        let leading = output.iter().take_while(|v| !v).count();
+        // An output of nothing but zeros is all leading, it can't be counted again as trailing
+        let trailing = output.iter().rev().take_while(|v| !v).count().min(output.len() - leading);
+        input.len() as i64 - (output.len() - leading - trailing) as i64
+    }
+}
+
+/// As if every run of equal bits cost a single bit to send
+#[derive(Debug)]
+pub struct RunCount;
+
+impl Fitness for RunCount {
+    fn name(&self) -> String { "run_count".to_string() }
+
+    fn score_block(&self, input: &BitVec, output: &BitVec) -> i64 {
+        input.len() as i64 - output.iter().coalesce(|a, b| if a == b { Ok(a) } else { Err((a, b)) }).count() as i64
+    }
+}
+
+/// Run length encoding, each run costing the bits to write its length plus one
+#[derive(Debug)]
+pub struct RunLengths;
+
+impl Fitness for RunLengths {
+    fn name(&self) -> String { "run_lengths".to_string() }
+
+    fn score_block(&self, input: &BitVec, output: &BitVec) -> i64 {
+        let cost: f64 = output.iter().chunk_by(|b| *b).into_iter().map(|(_, run)| (run.count() as f64).log2() + 1.0).sum();
+        input.len() as i64 - cost as i64
+    }
+}
+
+/// For programs that pad with alternating bits (fredkins ones): zeros leading the input's part of the output, less however much of
+/// the padding's part no longer alternates, as that'd need sending along too
+#[derive(Debug)]
+pub struct AlternatingPadding;
+
+impl Fitness for AlternatingPadding {
+    fn name(&self) -> String { "alternating_padding".to_string() }
+
+    fn score_block(&self, input: &BitVec, output: &BitVec) -> i64 {
+        let remainder_size = (output.len() - input.len()) as i64;
+        // The "unspoiled" region is however much of the padding still alternates, counting back from the end
+        let unspoiled = output.iter().enumerate().skip(input.len()).rev().take_while(|(p, v)| *v == (p % 2 == 0)).count() as i64;
+        output.iter().take(input.len()).take_while(|b| !b).count() as i64 - (remainder_size - unspoiled)
+    }
+}
+
+/// What an ideal static (two pass) entropy coder would spend on the outputs, each bit being coded with the probabilities its preceding
+/// `order` bits (within the same block, missing ones counting as zeros) had over the whole set. Order 0 is plain bit frequencies
+#[derive(Debug)]
+pub struct Entropy {
+    pub order: usize
+}
+
+impl Entropy {
+    /// Counts are kept for every context, so this is as far as it's sensible to go
+    pub const MAX_ORDER: usize = 20;
+
+    /// Empirical order-k entropy of all the outputs together, in bits
+    pub fn bits<'a>(&self, outputs: impl IntoIterator<Item = &'a BitVec>) -> f64 {
+        let mask = (1 << self.order) - 1;
+        let mut counts = vec![[0u64; 2]; 1 << self.order];
+        for output in outputs {
+            let mut context = 0;
+            for bit in output {
+                counts[context][bit as usize] += 1;
+                context = ((context << 1) | bit as usize) & mask;
+            }
+        }
+        counts.iter().map(|&[zeros, ones]| {
+            let total = (zeros + ones) as f64;
+            [zeros, ones].iter().filter(|&&n| n > 0).map(|&n| n as f64 * (total / n as f64).log2()).sum::<f64>()
+        }).sum()
+    }
+}
+
+impl Fitness for Entropy {
+    fn name(&self) -> String {
+        if self.order == 0 { "entropy".to_string() } else { format!("entropy:{}", self.order) }
+    }
+
+    fn score_block(&self, input: &BitVec, output: &BitVec) -> i64 {
+        input.len() as i64 - self.bits([output]).ceil() as i64
+    }
+
+    fn score(&self, inputs: &[BitVec], outputs: &[BitVec]) -> i64 {
+        inputs.iter().map(|i| i.len() as i64).sum::<i64>() - self.bits(outputs).ceil() as i64
+    }
+}
+
+/// What `arith` actually codes the outputs down to, with a context of `order` bits. The set is coded in frames the way `stream` does
+/// it, the model adapting as it goes through each, so it's scored on the bytes `stream`'s coded payload would take to send them
+#[derive(Debug)]
+pub struct Coded {
+    pub order: usize
+}
+
+impl Fitness for Coded {
+    fn name(&self) -> String {
+        if self.order == 0 { "coded".to_string() } else { format!("coded:{}", self.order) }
+    }
+
+    fn score_block(&self, input: &BitVec, output: &BitVec) -> i64 {
+        input.len() as i64 - 8 * arith::encode_all([output], self.order).len() as i64
+    }
+
+    fn score(&self, inputs: &[BitVec], outputs: &[BitVec]) -> i64 {
+        inputs.iter().map(|i| i.len() as i64).sum::<i64>() - 8 * stream::coded_len(inputs, outputs, self.order) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::arbitrairy_program::Program;
+    use crate::codec;
+
+    const NAMES: [&str; 4] = ["edge_zeros", "run_count", "run_lengths", "alternating_padding"];
+
+    fn bits(s: &str) -> BitVec {
+        s.chars().filter(|&c| c != ' ').map(|c| c == '1').collect()
+    }
+
+    #[test]
+    fn found_by_name() {
+        for name in NAMES {
+            assert_eq!(from_name(name).unwrap().name(), name);
+        }
+        for name in ["entropy", "entropy:3", "entropy:20", "coded", "coded:3"] {
+            assert_eq!(from_name(name).unwrap().name(), name);
+        }
+        for name in ["zeros", "entropy:21", "entropy:", "entropy:x", "coded:21", "edge_zeros:3"] {
+            assert!(from_name(name).is_none(), "Found {name}");
+        }
+    }
+
+    #[test]
+    fn scores_by_hand() {
+        let (input, output) = (bits("1010 1010"), bits("0011 1000"));
+        assert_eq!(EdgeZeros.score_block(&input, &output), 5);
+        assert_eq!(EdgeZeros.score_block(&input, &bits("0000 0000")), 8);
+        assert_eq!(RunCount.score_block(&input, &output), 5);
+        // Runs of 2, 3 and 3 cost 2 + 2.58 + 2.58 bits
+        assert_eq!(RunLengths.score_block(&input, &output), 1);
+        assert_eq!(AlternatingPadding.score_block(&bits("1111"), &bits("0010 1010")), 2);
+        // Only the last padding bit still alternates
+        assert_eq!(AlternatingPadding.score_block(&bits("1111"), &bits("0010 1000")), -1);
+        assert_eq!(Entropy { order: 0 }.bits([&bits("0011 0110")]), 8.0);
+        assert_eq!(Entropy { order: 0 }.bits([&bits("0000"), &bits("00")]), 0.0);
+        // Every bit is the opposite of the one before, bar the first
+        assert_eq!(Entropy { order: 1 }.bits([&bits("1010 1010")]), 0.0);
+    }
+
+    /// Every fitness has to score a set of blocks no differently from scoring them one at a time (bar entropy and coded, which look
+    /// at them all together), and edge_zeros has to agree with how many bits the codec actually keeps
+    #[test]
+    fn scores_blocks_separately() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut p = Program::<4, 16>::new(400);
+        for _ in 0..20 {
+            p = p.mutate(0.3, &mut rng);
+            let inputs: Vec<BitVec> = (0..8).map(|_| (0..rng.random_range(1..=400)).map(|_| rng.random_bool(0.1)).collect()).collect();
+            let outputs: Vec<BitVec> = inputs.iter().map(|i| p.forward(i.clone())).collect();
+            for name in NAMES {
+                let f = from_name(name).unwrap();
+                let blocks: i64 = inputs.iter().zip(&outputs).map(|(i, o)| f.score_block(i, o)).sum();
+                assert_eq!(evaluate(&*f, &p, &inputs), blocks);
+            }
+            for (input, output) in inputs.iter().zip(&outputs) {
+                let (leading, trailing) = codec::zero_runs(output);
+                assert_eq!(EdgeZeros.score_block(input, output), input.len() as i64 - (400 - leading - trailing) as i64);
+            }
+        }
+    }
+
+    /// Longer contexts only ever split the counts further, which can't make the empirical entropy go up
+    #[test]
+    fn entropy_never_rises_with_order() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for p_one in [0.05, 0.3, 0.5] {
+            let outputs: Vec<BitVec> = (0..8).map(|_| (0..400).map(|_| rng.random_bool(p_one)).collect()).collect();
+            let entropies = (0..=8).map(|order| Entropy { order }.bits(&outputs)).collect::<Vec<_>>();
+            assert!(entropies[0] <= (8 * 400) as f64 + 1e-6);
+            assert!(entropies.windows(2).all(|w| w[1] <= w[0] + 1e-6), "Entropy went up with order: {entropies:?}");
+        }
+    }
+
+    /// Coded outputs have to cost at least what the entropy says they can, and whole bytes of it
+    #[test]
+    fn coded_costs_at_least_the_entropy() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for p_one in [0.05, 0.3, 0.5] {
+            let outputs: Vec<BitVec> = (0..8).map(|_| (0..400).map(|_| rng.random_bool(p_one)).collect()).collect();
+            let inputs = outputs.clone();
+            for order in [0, 3] {
+                let score = Coded { order }.score(&inputs, &outputs);
+                assert_eq!(score % 8, 0);
+                assert!(score <= 8 * 400 - Entropy { order }.bits(&outputs).floor() as i64, "Coded:{order} scored {score} at {p_one}");
+            }
+        }
+    }
+
+    /// Past the header, end marker and checksum, which every stream has, a coded stream is just what `coded` scores. Over
+    /// several frames and with a short block at the end
+    #[test]
+    fn coded_scores_what_a_stream_takes() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let p = (0..5).fold(Program::<4, 16>::new(16), |p, _| p.mutate(0.3, &mut rng));
+        let data: Vec<u8> = (0..5001).map(|_| if rng.random_bool(0.8) { 0 } else { rng.random() }).collect();
+        let inputs = BitVec::from_bytes(&data).iter().chunks(16).into_iter().map(|chunk| chunk.collect()).collect_vec();
+        for order in [0, 4] {
+            let mut compressed = vec![];
+            stream::encode(&p, stream::Payload::Coded { order }, &mut &data[..], &mut compressed).unwrap();
+            let score = Coded { order }.score(&inputs, &p.forward_many(&inputs));
+            assert_eq!(8 * data.len() as i64 - score, 8 * (compressed.len() as i64 - 27), "Coded:{order} disagreed with the stream");
+        }
+    }
+}
@@ -1,24 +1,77 @@
+use std::ops::Range;
+
 use bit_vec::BitVec;
 use itertools::Itertools;
 use rand::Rng;
 
+use crate::circuit::{ReversibleCircuit, VerifyError};
+use crate::crossover::Crossover;
+use crate::serialization::{self, ByteReader, FormatError, Header, Kind};
+
+#[derive(Clone, Hash)]
 pub struct Program {
     fredkins: Vec<(usize, usize, usize)>,
     inp_size: usize
 }
 
 impl Program {
-    pub fn new(inp_size: usize) -> Self {
+    fn header(&self) -> Header {
+        Header { kind: Kind::Fredkins, gate_size: 0, inp_size: self.inp_size, gates: self.fredkins.len() }
+    }
+
+    fn gate_from_wires(wires: &[usize], inp_size: usize, gate: usize) -> Result<(usize, usize, usize), FormatError> {
+        let &[switch, g1, g2] = wires else {
+            return Err(FormatError::InvalidGate { gate, reason: format!("Expected 3 wires but found {}", wires.len()) });
+        };
+        serialization::check_wires(wires, inp_size, gate)?;
+        Ok((switch, g1, g2))
+    }
+
+    fn rectify_duplicates(&self, fredkins: &mut (usize, usize, usize)) {
+        // If they're all the same that breaks reversibility so... don't allow that
+        if fredkins.0 == fredkins.1 || fredkins.1 == fredkins.2 || fredkins.2 == fredkins.0 {
+            // Do this in a really awful way
+            /*fredkins.1 = (fredkins.0 + 1) % self.inp_size;
+            fredkins.2 = (fredkins.0 + 2) % self.inp_size;*/
+
+            // Slightly less awful way?
+            while (fredkins.0 == fredkins.1 || fredkins.0 == fredkins.2) {
+                fredkins.0 = (fredkins.0 + self.inp_size - 1) % self.inp_size; // Wrap round to the top rather than underflowing at wire 0
+            }
+            while (fredkins.2 == fredkins.0 || fredkins.2 == fredkins.1) {
+                fredkins.2 = (fredkins.2 + 1) % self.inp_size;
+            }
+        }
+        assert!(!(fredkins.0 == fredkins.1 || fredkins.1 == fredkins.2 || fredkins.2 == fredkins.0));
+        //println!("{}, {}, {}", fredkins.0, fredkins.1, fredkins.2);
+    }
+}
+
+impl ReversibleCircuit for Program {
+    #[cfg(test)]
+    const GATE_WIRES: usize = 3;
+
+    fn new(inp_size: usize) -> Self {
         Self {
             fredkins: vec![(0, 1, 4), (3, 1, 4), (2, 3, 1)],
             inp_size
         }
     }
 
-    pub fn forward(&self, mut input: BitVec) -> BitVec {
+    #[cfg(test)]
+    fn random(inp_size: usize, gates: usize, rng: &mut impl Rng) -> Self {
+        assert!(inp_size >= 3);
+        let fredkins = (0..gates).map(|_| {
+            let [switch, g1, g2] = rand::seq::index::sample(rng, inp_size, 3).into_iter().collect_array().unwrap();
+            (switch, g1, g2)
+        }).collect();
+        Self { fredkins, inp_size }
+    }
+
+    fn forward(&self, mut input: BitVec) -> BitVec {
         assert!(input.len() <= self.inp_size);
         while input.len() < self.inp_size {
-            input.push(input.len() % 2 == 0);
+            input.push(Self::padding(input.len()));
         }
         for &(switch, g1, g2) in &self.fredkins {
             assert_ne!(switch, g1);
@@ -38,70 +91,109 @@ impl Program {
         input
     }
 
-    pub fn eval(&self, input: &BitVec) -> i64 {
-        if self.fredkins.is_empty() {
-            return 0;
+    /// What inputs are padded with up to inp_size, alternating bits (see `fitness::AlternatingPadding`)
+    fn padding(wire: usize) -> bool {
+        wire % 2 == 0
+    }
+
+    fn forward_sliced(&self, wires: &mut [u64], gates: Range<usize>) {
+        assert_eq!(wires.len(), self.inp_size);
+        for &(switch, g1, g2) in &self.fredkins[gates] {
+            let (s, a, b) = (wires[switch], wires[g1], wires[g2]);
+            wires[g2] = (s & a) | (!s & b);
+            wires[g1] = (s & !b) | (!s & a);
         }
-        // To determine which strings were "touched", check only those that were altered
-        let max_string_touched = self.fredkins.iter().map(|f| std::cmp::max(f.1, f.2)).max().unwrap();
-        let res = self.forward(input.clone());
-        //println!("I got {}", res);
-        assert_eq!(res.len(), self.inp_size);
-        //let effective_outupt_size = std::cmp::max(max_string_touched + 1, input.len()); // If there are strings in no way involved, either in input or in calculation, ignore them as we could do the same in a real compression program
-        //res.iter().rev().skip(res.len() - effective_outupt_size).take_while(|b| !b).count() as i64 - (self.fredkins.len() / 2) as i64
-        // res.iter().take(effective_outupt_size).take_while(|b| !b).count() as i64/* - (self.fredkins.len() / 10) as i64*/
-        // Return the number of bits saved/eliminated by compression
-        let remainder_size = (self.inp_size - input.len()) as i64;
-        res.iter().take(input.len()).take_while(|b| !b).count() as i64 -
-            // Now find the length of the "unspoiled" region of alternating 0s and 1s at the right. Subtract the length of that, from the total length of the 0s-and-1s region, to yield the amount that was "spoiled", ie that we'd need to transmit alnog the wire as well
-            (remainder_size - res.iter().enumerate().skip(input.len()).rev().take_while(|(p, v)| *v == (p % 2 == 0)).count() as i64)
     }
 
-    pub fn eval_many(&self, inputs: impl IntoIterator<Item = impl std::borrow::Borrow<BitVec>>) -> i64 {
-        inputs.into_iter().map(|i| self.eval(i.borrow())).sum()
+    /// Undoes `forward`. Since the swap also negates, the inverse sends g1's value to g2 negated (rather than just swapping back), and the gates have to be undone last-first
+    fn backward(&self, mut output: BitVec) -> BitVec {
+        assert_eq!(output.len(), self.inp_size);
+        for &(switch, g1, g2) in self.fredkins.iter().rev() {
+            if output.get(switch).unwrap() {
+                let tmp = !output.get(g1).unwrap();
+                output.set(g1, output.get(g2).unwrap());
+                output.set(g2, tmp);
+            }
+        }
+        output
     }
 
-    fn eval_many_homogenizing(&self, inputs: impl IntoIterator<Item = impl std::borrow::Borrow<BitVec>>) -> i64 {
-        let res = inputs.into_iter().map(|i| self.eval(i.borrow())).collect_vec();
-        res.iter().sum::<i64>() - (res.iter().max().unwrap() - res.iter().min().unwrap()) * 4
+    fn inp_size(&self) -> usize {
+        self.inp_size
     }
 
+    fn gate_count(&self) -> usize {
+        self.fredkins.len()
+    }
 
-    fn rectify_duplicates(&self, fredkins: &mut (usize, usize, usize)) {
-        // If they're all the same that breaks reversibility so... don't allow that
-        if fredkins.0 == fredkins.1 || fredkins.1 == fredkins.2 || fredkins.2 == fredkins.0 {
-            // Do this in a really awful way
-            /*fredkins.1 = (fredkins.0 + 1) % self.inp_size;
-            fredkins.2 = (fredkins.0 + 2) % self.inp_size;*/
+    fn shared_prefix(&self, other: &Self) -> usize {
+        self.fredkins.iter().zip(&other.fredkins).take_while(|(a, b)| a == b).count()
+    }
 
-            // Slightly less awful way?
-            while (fredkins.0 == fredkins.1 || fredkins.0 == fredkins.2) {
-                fredkins.0 = (fredkins.0 - 1) % self.inp_size;
-            }
-            while (fredkins.2 == fredkins.0 || fredkins.2 == fredkins.1) {
-                fredkins.2 = (fredkins.2 + 1) % self.inp_size;
-            }
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        serialization::write_header(&mut out, self.header());
+        for &(switch, g1, g2) in &self.fredkins {
+            [switch, g1, g2].iter().for_each(|&w| out.extend_from_slice(&(w as u32).to_le_bytes()));
         }
-        assert!(!(fredkins.0 == fredkins.1 || fredkins.1 == fredkins.2 || fredkins.2 == fredkins.0));
-        //println!("{}, {}, {}", fredkins.0, fredkins.1, fredkins.2);
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, FormatError> {
+        let mut reader = ByteReader::new(bytes);
+        let header = serialization::read_header(&mut reader, Kind::Fredkins, 0)?;
+        let fredkins = (0..header.gates).map(|gate| {
+            let wires: Vec<usize> = (0..3).map(|_| reader.u32().map(|w| w as usize)).try_collect()?;
+            Self::gate_from_wires(&wires, header.inp_size, gate)
+        }).try_collect()?;
+        reader.finish()?;
+        Ok(Self { fredkins, inp_size: header.inp_size })
     }
 
-    pub fn mutation(&self, mut_rate: f64) -> Self {
-        let mut rng = rand::rng();
+    fn to_text(&self) -> String {
+        let mut text = serialization::write_text_header(self.header());
+        for (switch, g1, g2) in &self.fredkins {
+            text += &format!("gate {switch} {g1} {g2}\n");
+        }
+        text
+    }
+
+    fn from_text(text: &str) -> Result<Self, FormatError> {
+        let (header, lines) = serialization::read_text(text, Kind::Fredkins, 0)?;
+        let fredkins = lines.into_iter().enumerate()
+            .map(|(gate, (line, contents))| Self::gate_from_wires(&serialization::parse_all(contents, line)?, header.inp_size, gate))
+            .try_collect()?;
+        Ok(Self { fredkins, inp_size: header.inp_size })
+    }
+
+    /// Checks every gate's three wires are distinct and within inp_size, which is all it takes for a fredkins gate to be reversible
+    fn verify_gates(&self) -> Result<(), VerifyError> {
+        for (gate, &(switch, g1, g2)) in self.fredkins.iter().enumerate() {
+            serialization::check_wires(&[switch, g1, g2], self.inp_size, gate)?;
+        }
+        Ok(())
+    }
+
+    fn crossover(&self, other: &Self, crossover: Crossover, rng: &mut impl Rng) -> Self {
+        assert_eq!(self.inp_size, other.inp_size);
+        Self { fredkins: crossover.splice(&self.fredkins, &other.fredkins, |&(switch, g1, g2)| [switch, g1, g2], rng), inp_size: self.inp_size }
+    }
+
+    fn mutate(&self, mut_rate: f64, rng: &mut impl Rng) -> Self {
         let mut fredkins = self.fredkins.clone();
 
 
         for _ in 0..rng.sample(rand_distr::Binomial::new(fredkins.len() as u64, mut_rate).unwrap()) {
             // I feel like this should be able to be more compact
             let middle = rng.random_range(0..fredkins.len());
-            let size = std::cmp::min(rand::rng().sample(rand_distr::Normal::new(4.0, 4.0).unwrap()) as usize, std::cmp::min(fredkins.len() - middle - 1, middle - 0),
+            let size = std::cmp::min(rng.sample(rand_distr::Normal::new(4.0, 4.0).unwrap()) as usize, std::cmp::min(fredkins.len() - middle - 1, middle - 0),
             );
             //println!("Mid is {}, {}", middle, size);
 
 
             match rng.random_range(1..5) {
                 1 => { // Shift pos of group, vertical
-                    let shamt = rand::rng().sample(rand_distr::Normal::new(0.0, 3.0).unwrap()) as i64;
+                    let shamt = rng.sample(rand_distr::Normal::new(0.0, 3.0).unwrap()) as i64;
                     for elem in fredkins[middle - size..=middle + size].iter_mut() {
                         for conn in [&mut elem.0, &mut elem.1, &mut elem.2] {
                             *conn = (*conn as i64 + shamt).clamp(0, self.inp_size as i64 - 1) as usize;
@@ -110,7 +202,7 @@ impl Program {
                     }
                 }
                 2 => { // Horizontal copy
-                    let shamt = rand::rng().sample(rand_distr::Normal::new(0.0, 10.0 + (size * 4) as f64).unwrap()) as i64;
+                    let shamt = rng.sample(rand_distr::Normal::new(0.0, 10.0 + (size * 4) as f64).unwrap()) as i64;
                     let excerpt = fredkins[middle - size..=middle + size].to_owned();
                     let insertion_point = (middle as i64 + shamt + size as i64 * shamt.signum()).clamp(0, fredkins.len() as i64 - 1) as usize;
                     let tmp = fredkins.splice(insertion_point..=insertion_point, excerpt).next().unwrap();
@@ -128,7 +220,7 @@ impl Program {
                     let dist = rand_distr::Normal::new(0.0, 8.0).unwrap();
                     for elem in fredkins[middle - size..=middle + size].iter_mut() {
                         for conn in [&mut elem.0, &mut elem.1, &mut elem.2] {
-                            *conn = (*conn as i64 + rand::rng().sample(dist) as i64).clamp(0, self.inp_size as i64 - 1) as usize;
+                            *conn = (*conn as i64 + rng.sample(dist) as i64).clamp(0, self.inp_size as i64 - 1) as usize;
                         }
                         self.rectify_duplicates(elem);
                     }
@@ -145,8 +237,106 @@ impl Program {
         };
         Self {fredkins, inp_size: self.inp_size}
     }
-    
-    pub fn complexity(&self) -> i64 {
+
+    fn complexity(&self) -> i64 {
         self.fredkins.len() as i64
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::circuit::{ReversibleCircuit, VerifyError};
+    use crate::crossover::{Alignment, Points};
+
+    /// Up to 100 gates on distinct random wires
+    fn random_program(inp_size: usize, rng: &mut impl Rng) -> Program {
+        let fredkins = (0..rng.random_range(1..=100)).map(|_| {
+            let wires = rand::seq::index::sample(rng, inp_size, 3);
+            (wires.index(0), wires.index(1), wires.index(2))
+        }).collect();
+        Program { fredkins, inp_size }
+    }
+
+    /// On random gates rather than a mutated lineage, so this only depends on `backward`
+    #[test]
+    fn backward_undoes_forward() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..200 {
+            let p = random_program(400, &mut rng);
+            for _ in 0..4 {
+                let input: BitVec = (0..400).map(|_| rng.random_bool(0.5)).collect();
+                assert_eq!(p.backward(p.forward(input.clone())), input, "backward(forward(x)) != x");
+            }
+        }
+    }
+
+    /// The swap negates, so a gate applied twice doesn't give back what went in, only its inverse does
+    #[test]
+    fn gates_are_not_self_inverse() {
+        let p = Program { fredkins: vec![(0, 1, 2)], inp_size: 3 };
+        let input = BitVec::from_fn(3, |i| i != 2);
+        assert_eq!(p.forward(p.forward(input.clone())), BitVec::from_fn(3, |i| i != 1));
+        assert_eq!(p.backward(p.forward(input.clone())), input);
+    }
+
+    #[test]
+    fn saves_and_reloads() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..100 {
+            let p = random_program(400, &mut rng);
+            let bytes = p.to_bytes();
+            assert_eq!(Program::from_bytes(&bytes).unwrap().to_bytes(), bytes);
+            assert_eq!(Program::from_text(&p.to_text()).unwrap().to_bytes(), bytes);
+            assert!(Program::from_bytes(&bytes[..rng.random_range(0..bytes.len())]).is_err(), "Truncated program was accepted");
+        }
+    }
+
+    #[test]
+    fn refuses_other_kinds() {
+        let arbitrairy = crate::arbitrairy_program::Program::<4, 16>::new(8);
+        assert!(matches!(Program::from_bytes(&arbitrairy.to_bytes()), Err(FormatError::WrongKind { .. })));
+        assert!(matches!(Program::from_text(&arbitrairy.to_text()), Err(FormatError::WrongKind { .. })));
+    }
+
+    #[test]
+    fn crossover_children_are_valid() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..50 {
+            let (a, b) = (random_program(400, &mut rng), random_program(400, &mut rng));
+            for points in [Points::One, Points::Two] {
+                for alignment in [Alignment::Position, Alignment::Wires] {
+                    let child = a.crossover(&b, Crossover { points, alignment }, &mut rng);
+                    assert_eq!(Program::from_bytes(&child.to_bytes()).unwrap().to_bytes(), child.to_bytes());
+                    let input: BitVec = (0..400).map(|_| rng.random_bool(0.5)).collect();
+                    assert_eq!(child.backward(child.forward(input.clone())), input);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn verifies_small_programs_exhaustively() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..50 {
+            assert!(random_program(10, &mut rng).verify().unwrap());
+        }
+        let p = Program { fredkins: vec![(0, 1, 2), (2, 0, 2)], inp_size: 10 };
+        assert!(matches!(p.verify(), Err(VerifyError::BadGate(FormatError::InvalidGate { gate: 1, .. }))));
+    }
+
+    /// Short inputs get the alternating padding, which the sliced version has to fill in the same way
+    #[test]
+    fn forward_many_agrees_with_forward() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..20 {
+            let p = random_program(130, &mut rng);
+            let inputs: Vec<BitVec> = (0..rng.random_range(0..=150)).map(|_| (0..rng.random_range(0..=130)).map(|_| rng.random_bool(0.5)).collect()).collect();
+            let expected: Vec<BitVec> = inputs.iter().map(|i| p.forward(i.clone())).collect();
+            assert!(p.forward_many(&inputs) == expected, "forward_many disagreed with forward");
+        }
+    }
+}
@@ -0,0 +1,86 @@
+//! Property tests of the programs: random programs (down to a single gate on only just enough wires) are put through chains of
+//! mutations and crossovers, checking nothing panics and every program along the way is still reversible. Every case has its own seed,
+//! which failures are reported with
+
+use bit_vec::BitVec;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::circuit::ReversibleCircuit;
+use crate::{arbitrairy_program, fredkins_program};
+use crate::crossover::{Alignment, Crossover, Points};
+
+/// Chains run for each kind of program
+const CASES: u64 = 100;
+/// Mutations in each chain
+const CHAIN_LENGTH: usize = 30;
+/// Largest inp_size over the least the program can have
+const EXTRA_WIRES: usize = 40;
+
+fn chains<C: ReversibleCircuit>() {
+    for case in 0..CASES {
+        chain::<C>(case);
+    }
+}
+
+fn chain<C: ReversibleCircuit>(case: u64) {
+    let rng = &mut StdRng::seed_from_u64(case);
+    let inp_size = rng.random_range(C::GATE_WIRES..=C::GATE_WIRES + EXTRA_WIRES);
+    let mut p = C::random(inp_size, rng.random_range(1..=20), rng);
+    check(&p, case, rng);
+    for _ in 0..CHAIN_LENGTH {
+        p = if rng.random_bool(0.2) {
+            let other = C::random(inp_size, rng.random_range(1..=20), rng);
+            let points = if rng.random() { Points::One } else { Points::Two };
+            let alignment = if rng.random() { Alignment::Position } else { Alignment::Wires };
+            p.crossover(&other, Crossover { points, alignment }, rng)
+        } else {
+            // Including the extremes, which is where index arithmetic goes wrong
+            let rate = match rng.random_range(0..4) {
+                0 => 0.0,
+                1 => 1.0,
+                _ => rng.random()
+            };
+            p.mutate(rate, rng)
+        };
+        check(&p, case, rng);
+    }
+}
+
+/// Exhaustive when the program is small enough to do quickly, otherwise on random inputs of every length up to inp_size
+fn check<C: ReversibleCircuit>(p: &C, case: u64, rng: &mut StdRng) {
+    if let Err(e) = p.verify_gates() {
+        panic!("Case {case}: {e} in\n{}", p.to_text());
+    }
+    if p.inp_size() <= 10 && let Err(e) = p.verify() {
+        panic!("Case {case}: {e} in\n{}", p.to_text());
+    }
+    for _ in 0..8 {
+        let input: BitVec = (0..rng.random_range(0..=p.inp_size())).map(|_| rng.random()).collect();
+        let output = p.forward(input.clone());
+        assert_eq!(output.len(), p.inp_size(), "Case {case}");
+        let back = p.backward(output);
+        assert!(back.iter().take(input.len()).eq(&input), "Case {case}: backward(forward({input})) gave {back} in\n{}", p.to_text());
+    }
+    assert_eq!(C::from_bytes(&p.to_bytes()).unwrap().to_bytes(), p.to_bytes(), "Case {case}");
+}
+
+#[test]
+fn arbitrairy_2_chains() {
+    chains::<arbitrairy_program::Program<2, 4>>();
+}
+
+#[test]
+fn arbitrairy_3_chains() {
+    chains::<arbitrairy_program::Program<3, 8>>();
+}
+
+#[test]
+fn arbitrairy_4_chains() {
+    chains::<arbitrairy_program::Program<4, 16>>();
+}
+
+#[test]
+fn fredkins_chains() {
+    chains::<fredkins_program::Program>();
+}
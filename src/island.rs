@@ -0,0 +1,147 @@
+//! Island model: the search split into islands that each breed and select on their own, sending copies of a few of their best to
+//! other islands every so often. One lineage taking over an island then doesn't take over the whole search
+
+use itertools::Itertools;
+
+use crate::circuit::ReversibleCircuit;
+use crate::trace::Trace;
+
+/// Which islands send migrants to which
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Topology {
+    /// Each island to the next, the last to the first
+    Ring,
+    /// Every island to every other
+    Full
+}
+
+impl Topology {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "ring" => Some(Self::Ring),
+            "full" => Some(Self::Full),
+            _ => None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Ring => "ring",
+            Self::Full => "full"
+        }
+    }
+
+    /// Whether island `from` sends its migrants to island `to`, out of `islands`
+    pub fn sends_to(self, from: usize, to: usize, islands: usize) -> bool {
+        match self {
+            Self::Ring => to == (from + 1) % islands && to != from,
+            Self::Full => to != from
+        }
+    }
+}
+
+/// One island's elites, best first, along with their traces and scores
+#[derive(Clone)]
+pub struct Island<C: ReversibleCircuit> {
+    pub bests: Vec<C>,
+    /// Worked out again when missing (at the start and after resuming)
+    pub traces: Vec<Trace>,
+    /// Empty until the island's first generation
+    pub scores: Vec<i64>
+}
+
+impl<C: ReversibleCircuit> Island<C> {
+    pub fn new(bests: Vec<C>) -> Self {
+        Self { bests, traces: vec![], scores: vec![] }
+    }
+
+    /// Best score, elites' mean score, how many of the elites are different programs and how big the best is
+    pub fn stats(&self) -> String {
+        let distinct = self.bests.iter().map(C::structural_hash).unique().count();
+        format!("best {}, elites' mean {:.1}, {distinct} distinct of {} elites, best has {} gates",
+                self.scores.first().map_or("?".to_string(), i64::to_string),
+                self.scores.iter().sum::<i64>() as f64 / self.scores.len().max(1) as f64,
+                self.bests.len(),
+                self.bests[0].complexity())
+    }
+
+    /// Takes in `arriving` in place of the worst elites (but never the best), keeping them in order best first
+    fn welcome(&mut self, mut arriving: Vec<(C, Trace, i64)>) {
+        let elites = self.bests.len();
+        arriving.sort_by_key(|&(_, _, score)| -score);
+        let keep = elites.saturating_sub(arriving.len()).max(1);
+        let members = std::mem::take(&mut self.bests).into_iter().zip(std::mem::take(&mut self.traces)).zip(std::mem::take(&mut self.scores))
+            .map(|((program, trace), score)| (program, trace, score)).take(keep).chain(arriving).take(elites)
+            // Stable, so on a tie an island's own elites stay ahead of the newcomers
+            .sorted_by_key(|&(_, _, score)| -score);
+        for (program, trace, score) in members {
+            self.bests.push(program);
+            self.traces.push(trace);
+            self.scores.push(score);
+        }
+    }
+}
+
+/// Sends copies of each island's best `migrants` along `topology`. They're all picked before any arrive, so nothing moves twice
+pub fn migrate<C: ReversibleCircuit>(islands: &mut [Island<C>], topology: Topology, migrants: usize) {
+    let n = islands.len();
+    let leaving = islands.iter().map(|island| {
+        (0..migrants.min(island.bests.len())).map(|i| (island.bests[i].clone(), island.traces[i].clone(), island.scores[i])).collect_vec()
+    }).collect_vec();
+    for (to, island) in islands.iter_mut().enumerate() {
+        island.welcome((0..n).filter(|&from| topology.sends_to(from, to, n)).flat_map(|from| leaving[from].iter().cloned()).collect());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::arbitrairy_program::Program;
+    use crate::circuit::Sliced;
+
+    #[test]
+    fn topologies_by_hand() {
+        for topology in [Topology::Ring, Topology::Full] {
+            assert_eq!(Topology::from_name(topology.name()), Some(topology));
+        }
+        assert_eq!(Topology::from_name("star"), None);
+        let sends = |topology: Topology, n| (0..n).cartesian_product(0..n).filter(|&(from, to)| topology.sends_to(from, to, n)).collect_vec();
+        assert_eq!(sends(Topology::Ring, 3), [(0, 1), (1, 2), (2, 0)]);
+        assert_eq!(sends(Topology::Full, 3), [(0, 1), (0, 2), (1, 0), (1, 2), (2, 0), (2, 1)]);
+        assert!(sends(Topology::Ring, 1).is_empty() && sends(Topology::Full, 1).is_empty());
+    }
+
+    /// Migrating has to keep every island's size and best, leave each island best first, and bring each island the migrants its
+    /// topology says it gets
+    #[test]
+    fn migrants_replace_the_worst() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let start = Sliced::pack::<Program<4, 16>>(&[], 20);
+        for _ in 0..200 {
+            let topology = if rng.random() { Topology::Ring } else { Topology::Full };
+            let (n, migrants) = (rng.random_range(1..6), rng.random_range(0..4));
+            let mut islands = (0..n).map(|_| {
+                let elites = rng.random_range(migrants + 1..10);
+                let bests = (0..elites).map(|_| Program::<4, 16>::random(20, rng.random_range(1..5), &mut rng)).collect_vec();
+                let traces = bests.iter().map(|p| Trace::new(p, &start, 0)).collect();
+                Island { bests, traces, scores: (0..elites).map(|_| rng.random_range(0..20)).sorted_by_key(|&s| -s).collect() }
+            }).collect_vec();
+            let before = islands.clone();
+            migrate(&mut islands, topology, migrants);
+            for (to, (island, old)) in islands.iter().zip(&before).enumerate() {
+                assert_eq!(island.bests.len(), old.bests.len());
+                assert_eq!(island.traces.len(), old.bests.len());
+                let best_arriving = (0..n).filter(|&from| topology.sends_to(from, to, n) && migrants > 0).map(|from| before[from].scores[0]).max();
+                assert_eq!(island.scores[0], old.scores[0].max(best_arriving.unwrap_or(i64::MIN)));
+                assert!(island.scores.is_sorted_by_key(|&s| -s), "Island isn't best first after migrating");
+                assert!(island.bests.iter().any(|p| p.to_bytes() == old.bests[0].to_bytes()), "Island lost its best to migrants");
+                let arrived = (0..n).filter(|&from| topology.sends_to(from, to, n)).count() * migrants;
+                let stayed = island.bests.iter().filter(|p| old.bests.iter().any(|q| q.to_bytes() == p.to_bytes())).count();
+                assert!(stayed >= old.bests.len().saturating_sub(arrived).max(1));
+            }
+        }
+    }
+}
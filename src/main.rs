@@ -1,69 +1,171 @@
 mod fredkins_program;
 mod arbitrairy_program;
-
-use std::cmp::max;
-use rayon::iter::ParallelIterator;
+mod codec;
+mod container;
+mod serialization;
+mod checkpoint;
+mod search;
+mod corpus;
+mod stream;
+mod circuit;
+mod crossover;
+mod fitness;
+mod arith;
+#[cfg(test)]
+mod fuzz;
+mod pareto;
+mod selection;
+mod trace;
+mod cache;
+mod island;
+
+use std::{fs, io};
 use bit_vec::BitVec;
-use rand::{rng, Rng};
 use itertools::Itertools;
-use rand::seq::IndexedRandom;
-use rayon::iter::IntoParallelIterator;
 
+use circuit::ReversibleCircuit;
+use corpus::Corpus;
+use search::{Search, SearchConfig};
+use serialization::Kind;
 
 // What would it mean if the mutations could become mulpitlicative in the same language, as mutations?
 // E.g. delete this section of gene, clone this other section - at "phyolgeny" time
 // And then perhaps had the ability to expand those out into the genes on which we do evolution - is that meaningfuly/useful? Does this just amount to an encoding of a tree structure?
 
-pub fn eval(forward: impl Fn(BitVec) -> BitVec, input: &BitVec) -> i64 {
-    let res = forward(input.clone());
-    // It is assumed that res is the untruncated output of the program here, it should be equal in size to the program's internal input size
-    /*let remainder_size = (res.len() - input.len()) as i64;
-    res.iter().take(input.len()).take_while(|b| !b).count() as i64 -
-        // Now find the length of the "unspoiled" region of alternating 0s and 1s at the right. Subtract the length of that, from the total length of the 0s-and-1s region, to yield the amount that was "spoiled", ie that we'd need to transmit alnog the wire as well
-        (remainder_size - res.iter().enumerate().skip(input.len()).rev().take_while(|(p, v)| *v == (p % 2 == 0)).count() as i64)*/
-
-    //input.len() as i64 - res.iter().coalesce(|a, b| if a == b {Ok(a)} else {Err((a, b))}).count() as i64
-
-    let zeros = res.iter().take_while(|v| *v == false).count() as i64 + res.iter().rev().take_while(|v| *v == false).count() as i64;
-    input.len() as i64 - (res.len() as i64 - zeros)
+const USAGE: &str = "Usage:
+    ReversibleThing [search] [--config <file>] [--<option> <value>]... [--resume]
+    ReversibleThing compress <program file> <input> <output> [--payload stripped|coded|coded:<order>]
+    ReversibleThing decompress <program file> <input> <output>
+    ReversibleThing verify <program file>
+Search options (in a config file as `option = value`): population, elites, mutation_rate, crossover_rate, crossover,
+    crossover_alignment, fitness, complexity_penalty, selection, trace_every, cache_size, islands, migration, migration_interval, migrants,
+    front_dir, inp_size, program, gate_size, generations, seed, report_every, save, checkpoint_dir, checkpoint_every, resume, corpus, validation_fraction";
+
+/// Gate size is a const generic, so every kind and size of program that can be picked at runtime has to be instantiated here
+macro_rules! with_circuit {
+    ($kind:expr, $gate_size:expr, |$c:ident| $body:expr) => {
+        match ($kind, $gate_size) {
+            (Kind::Fredkins, _) => { type $c = fredkins_program::Program; $body }
+            (Kind::Arbitrairy, 2) => { type $c = arbitrairy_program::Program<2, 4>; $body }
+            (Kind::Arbitrairy, 3) => { type $c = arbitrairy_program::Program<3, 8>; $body }
+            (Kind::Arbitrairy, 4) => { type $c = arbitrairy_program::Program<4, 16>; $body }
+            (Kind::Arbitrairy, 5) => { type $c = arbitrairy_program::Program<5, 32>; $body }
+            (Kind::Arbitrairy, 6) => { type $c = arbitrairy_program::Program<6, 64>; $body }
+            (_, n) => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unsupported gate size {n}, only 2 to 6 are available")))
+        }
+    };
+}
 
-    /*let x: f64 = res.iter().chunk_by(|i| *i).into_iter().map(|(v, group)| f64::log2(group.collect_vec().len() as f64) + 1f64).sum();
-    input.len() as i64 - x as i64*/
+fn main() {
+    let args = std::env::args().skip(1).collect_vec();
+    let res = match args.iter().map(String::as_str).collect_vec().as_slice() {
+        [] => search(SearchConfig::default()),
+        ["search", flags @ ..] => match SearchConfig::from_args(flags) {
+            Ok(config) => search(config),
+            Err(e) => {
+                eprintln!("{e}\n{USAGE}");
+                std::process::exit(2);
+            }
+        },
+        ["compress", program, input, output] => compress_file(program, input, output, stream::Payload::Stripped),
+        ["compress", program, input, output, "--payload", payload] => match stream::Payload::from_name(payload) {
+            Some(payload) => compress_file(program, input, output, payload),
+            None => Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown payload `{payload}`, expected stripped, coded or coded:<order>")))
+        },
+        ["decompress", program, input, output] => decompress_file(program, input, output),
+        ["verify", program] => verify_file(program),
+        _ => {
+            eprintln!("{USAGE}");
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = res {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 }
 
-pub fn eval_many(forward: impl Fn(BitVec) -> BitVec + Copy, inputs: impl IntoIterator<Item = impl std::borrow::Borrow<BitVec>>) -> i64 {
-    inputs.into_iter().map(|i| eval(forward, i.borrow())).sum()
+fn program_kind(path: &str) -> io::Result<(Kind, usize)> {
+    serialization::peek(&fs::read(path)?).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("{path}: Not a saved program")))
 }
 
+fn compress_file(program: &str, input: &str, output: &str, payload: stream::Payload) -> io::Result<()> {
+    let (kind, gate_size) = program_kind(program)?;
+    let read = with_circuit!(kind, gate_size, |C| {
+        let program = C::load(program.as_ref())?;
+        let mut reader = io::BufReader::new(fs::File::open(input)?);
+        write_via_temp(output, |writer| stream::encode(&program, payload, &mut reader, writer))
+    })?;
+    println!("{} -> {} bytes", read, fs::metadata(output)?.len());
+    Ok(())
+}
 
-fn main() {
-    let tests = (0..222 as u8).map(|s| [s, s+1,s+2,s+3,s+4,s+5,s+6,s+7,s+8]).map(|v| BitVec::from_bytes(&v)).collect_vec();
+fn decompress_file(program: &str, input: &str, output: &str) -> io::Result<()> {
+    let (kind, gate_size) = program_kind(program)?;
+    let mut reader = io::BufReader::new(fs::File::open(input)?);
+    with_circuit!(kind, gate_size, |C| {
+        let program = C::load(program.as_ref())?;
+        // Blocks are written out as they're decoded, before the checksum at the end has been checked
+        write_via_temp(output, |writer| stream::decode(&program, &mut reader, writer).map_err(io::Error::from))
+    })?;
+    Ok(())
+}
 
+/// Writes to a temp file next to `output` and only renames it into place once `write` has succeeded, deleting it otherwise, so a
+/// failure never leaves a partial (or unchecked) file behind or clobbers one that was already there
+fn write_via_temp<T>(output: &str, write: impl FnOnce(&mut io::BufWriter<fs::File>) -> io::Result<T>) -> io::Result<T> {
+    let temp = format!("{output}.partial");
+    let res = fs::File::create(&temp).and_then(|file| {
+        let mut writer = io::BufWriter::new(file);
+        let value = write(&mut writer)?;
+        writer.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+        fs::rename(&temp, output)?;
+        Ok(value)
+    });
+    if res.is_err() {
+        let _ = fs::remove_file(&temp);
+    }
+    res
+}
 
-    //let mut best = Program::new(120);
-    let mut bests = vec![arbitrairy_program::Program::<4, 16>::new(400)];
+/// Loading already checks the gates, this also runs every input through if there aren't too many
+fn verify_file(program: &str) -> io::Result<()> {
+    let (kind, gate_size) = program_kind(program)?;
+    let exhaustive = with_circuit!(kind, gate_size, |C| {
+        C::load(program.as_ref())?.verify().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{program}: {e}")))
+    })?;
+    if exhaustive {
+        println!("{program} is reversible, checked on every input");
+    } else {
+        println!("{program}'s gates are all reversible (inp_size is too large to try every input)");
+    }
+    Ok(())
+}
 
-    let mut i: u64 = 0;
-    loop {
-        let scores: Vec<(arbitrairy_program::Program<4, 16>, i64)> = (0..500).map(|_| bests.choose(&mut rng()).unwrap().mutation(0.1)).collect_vec().into_iter().chain(bests).
-            collect_vec().into_par_iter().map(|p| {
-            let score = eval_many(|i| p.forward(i), &tests) - (p.complexity() as i64 * 4);
-            (p, score)
-        }).collect();
-        if i % 100 == 0 {
-            println!("{:?}", scores.iter().map(|s| s.1).collect_vec());
+fn search(config: SearchConfig) -> io::Result<()> {
+    let corpus = if config.corpus.is_empty() {
+        if config.inp_size < 72 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "The built in counting sequences are 72 bits long, so need an inp_size of at least 72"));
         }
-        bests = scores.into_iter().sorted_by_key(|(p, score)| -*score).take(10).map(|(p, _)| p).collect_vec();
-        if i % 100 == 0 {
-            println!("Best {}, did {} (namely {}), got:\n{}",
-                     bests[0].complexity(),
-                     eval_many(|i| bests[0].forward(i), &tests),
-                     tests.iter().map(|t| eval(|i| bests[0].forward(i), &t)).map(|v| format!("{}", v)).intersperse(",".to_string()).collect::<String>(),
-                     bests[0].forward(tests[0].clone())
-            );
+        let tests = (0..222 as u8).map(|s| [s, s+1,s+2,s+3,s+4,s+5,s+6,s+7,s+8]).map(|v| BitVec::from_bytes(&v)).collect_vec();
+        Corpus { train: tests, validation: vec![] }
+    } else {
+        // The split is seeded separately from the search, so it stays the same across runs of the same corpus whatever their seeds
+        let corpus = Corpus::load(&config.corpus, config.inp_size, config.validation_fraction, 0)?;
+        println!("Loaded {} training and {} validation blocks", corpus.train.len(), corpus.validation.len());
+        corpus
+    };
+
+    with_circuit!(config.program, config.gate_size, |C| {
+        let mut search = Search::<C>::new(config.clone(), corpus);
+        if config.resume {
+            match search.resume()? {
+                true => println!("Resuming from generation {}", search.generation()),
+                false => println!("No checkpoint to resume from, starting afresh")
+            }
         }
-        i += 1;
-    }
+        search.run()
+    })
 }
 
 /*
@@ -0,0 +1,163 @@
+//! NSGA-II style selection, so the search can keep every trade-off between compression and size rather than fixing one up front
+
+use itertools::Itertools;
+
+/// What a program is judged on, none of them traded off against each other
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Objectives {
+    /// Bits saved on the training set (more is better)
+    pub saved: i64,
+    /// Size of the program (less is better)
+    pub gates: i64,
+    /// Variance of the bits saved on each block (less is better), so programs that do well on everything beat ones that happen
+    /// to do very well on a few blocks
+    pub variance: f64
+}
+
+impl Objectives {
+    /// At least as good on everything and better on something
+    pub fn dominates(&self, other: &Self) -> bool {
+        self.saved >= other.saved && self.gates <= other.gates && self.variance <= other.variance && self != other
+    }
+
+    /// Sample variance of per-block scores
+    pub fn variance_of(scores: &[i64]) -> f64 {
+        if scores.is_empty() {
+            return 0.0;
+        }
+        let mean = scores.iter().sum::<i64>() as f64 / scores.len() as f64;
+        scores.iter().map(|&s| (s as f64 - mean).powi(2)).sum::<f64>() / scores.len() as f64
+    }
+}
+
+/// Splits the points into non-dominated fronts, best first: the first front is everything nothing else dominates, the second
+/// everything only the first dominates, and so on. Each front lists its indexes in increasing order
+pub fn fronts(points: &[Objectives]) -> Vec<Vec<usize>> {
+    let mut dominated_by = vec![0usize; points.len()];
+    let mut dominates = vec![vec![]; points.len()];
+    for (i, j) in (0..points.len()).tuple_combinations() {
+        if points[i].dominates(&points[j]) {
+            dominates[i].push(j);
+            dominated_by[j] += 1;
+        } else if points[j].dominates(&points[i]) {
+            dominates[j].push(i);
+            dominated_by[i] += 1;
+        }
+    }
+    let mut fronts = vec![];
+    let mut front = (0..points.len()).filter(|&i| dominated_by[i] == 0).collect_vec();
+    while !front.is_empty() {
+        let mut next = vec![];
+        for &i in &front {
+            for &j in &dominates[i] {
+                dominated_by[j] -= 1;
+                if dominated_by[j] == 0 {
+                    next.push(j);
+                }
+            }
+        }
+        next.sort_unstable();
+        fronts.push(std::mem::replace(&mut front, next));
+    }
+    fronts
+}
+
+/// How much room each member of `front` has around it, as the sum over objectives of the (normalised) gap between its neighbours.
+/// The extremes of each objective get infinity so they're always kept
+pub fn crowding_distances(points: &[Objectives], front: &[usize]) -> Vec<f64> {
+    let mut distances = vec![0.0; front.len()];
+    let objectives: [fn(&Objectives) -> f64; 3] = [|o| o.saved as f64, |o| o.gates as f64, |o| o.variance];
+    for objective in objectives {
+        let order = (0..front.len()).sorted_by(|&a, &b| objective(&points[front[a]]).total_cmp(&objective(&points[front[b]]))).collect_vec();
+        let (min, max) = (objective(&points[front[order[0]]]), objective(&points[front[*order.last().unwrap()]]));
+        distances[order[0]] = f64::INFINITY;
+        distances[*order.last().unwrap()] = f64::INFINITY;
+        if max > min {
+            for w in order.windows(3) {
+                distances[w[1]] += (objective(&points[front[w[2]]]) - objective(&points[front[w[0]]])) / (max - min);
+            }
+        }
+    }
+    distances
+}
+
+/// Picks up to `n` of the points: whole fronts in order while they fit, then the least crowded of the front that doesn't.
+/// Returns them grouped by front, best front first
+pub fn select(points: &[Objectives], n: usize) -> Vec<Vec<usize>> {
+    let mut selected = vec![];
+    let mut left = n;
+    for front in fronts(points) {
+        if left == 0 {
+            break;
+        }
+        if front.len() <= left {
+            left -= front.len();
+            selected.push(front);
+        } else {
+            let distances = crowding_distances(points, &front);
+            let keep = (0..front.len()).sorted_by(|&a, &b| distances[b].total_cmp(&distances[a]).then(a.cmp(&b))).take(left).map(|i| front[i]).sorted().collect_vec();
+            selected.push(keep);
+            break;
+        }
+    }
+    selected
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn point(saved: i64, gates: i64, variance: f64) -> Objectives {
+        Objectives { saved, gates, variance }
+    }
+
+    #[test]
+    fn dominates_by_hand() {
+        assert!(point(5, 3, 1.0).dominates(&point(4, 3, 1.0)));
+        assert!(point(5, 2, 1.0).dominates(&point(5, 3, 2.0)));
+        assert!(!point(5, 3, 1.0).dominates(&point(5, 3, 1.0)), "Points dominate themselves");
+        assert!(!point(5, 3, 1.0).dominates(&point(4, 2, 1.0)));
+        assert_eq!(Objectives::variance_of(&[]), 0.0);
+        assert_eq!(Objectives::variance_of(&[1, 3, 5, 7]), 5.0);
+    }
+
+    /// Random points (with plenty of ties) sort into the fronts NSGA-II says they should, and selection takes them in order
+    #[test]
+    fn fronts_are_layers_of_domination() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..200 {
+            let objectives: Vec<Objectives> = (0..rng.random_range(1..=60)).map(|_| point(rng.random_range(0..10), rng.random_range(0..10), rng.random_range(0..10) as f64)).collect();
+            let fronts = fronts(&objectives);
+            assert_eq!(fronts.iter().map(Vec::len).sum::<usize>(), objectives.len(), "Fronts don't cover every point exactly once");
+            for (k, front) in fronts.iter().enumerate() {
+                for &i in front {
+                    assert!(!front.iter().any(|&j| objectives[j].dominates(&objectives[i])), "Point dominated by its own front");
+                    if k > 0 {
+                        assert!(fronts[k - 1].iter().any(|&j| objectives[j].dominates(&objectives[i])), "Point not dominated by the front before");
+                    }
+                }
+            }
+            let n = rng.random_range(1..=objectives.len());
+            let selected = select(&objectives, n);
+            assert_eq!(selected.iter().map(Vec::len).sum::<usize>(), n);
+            // Whole fronts in order, then part of the next
+            let (last, whole) = selected.split_last().unwrap();
+            assert!(whole.iter().zip(&fronts).all(|(s, f)| s == f), "Selection skipped part of a front it had room for");
+            assert!(last.iter().all(|i| fronts[whole.len()].contains(i)));
+        }
+    }
+
+    /// Along a straight front the ends are always kept, then whichever has the most room around it
+    #[test]
+    fn keeps_the_extremes_and_the_least_crowded() {
+        let line = [point(0, 0, 0.0), point(1, 1, 0.0), point(2, 2, 0.0), point(6, 6, 0.0), point(10, 10, 0.0)];
+        let distances = crowding_distances(&line, &[0, 1, 2, 3, 4]);
+        assert!(distances[0].is_infinite() && distances[4].is_infinite());
+        assert_eq!(&distances[1..4], [0.4, 1.0, 1.6]);
+        assert_eq!(select(&line, 3), [vec![0, 3, 4]]);
+        assert_eq!(select(&line, 5), [vec![0, 1, 2, 3, 4]]);
+    }
+}
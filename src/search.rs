@@ -0,0 +1,652 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bit_vec::BitVec;
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use crate::cache::{self, FitnessCache, Key};
+use crate::circuit::{ReversibleCircuit, Sliced};
+use crate::checkpoint::{self, Checkpoint};
+use crate::corpus::Corpus;
+use crate::crossover::{Alignment, Crossover, Points};
+use crate::serialization::Kind;
+use crate::fitness::{self, Fitness};
+use crate::island::{self, Island, Topology};
+use crate::pareto::{self, Objectives};
+use crate::selection::{self, Selection};
+use crate::trace::Trace;
+
+/// Everything about a run that used to be a literal in `main`.
+/// Loaded from a file of `key = value` lines (`#` for comments), the same keys can then be overridden on the command line as `--key value`
+#[derive(Clone, Debug)]
+pub struct SearchConfig {
+    /// Children bred each generation
+    pub population: usize,
+    /// How many of the best (out of the children and the previous elites) survive to breed the next generation
+    pub elites: usize,
+    pub mutation_rate: f64,
+    /// Chance each child is bred from a pair of parents rather than just one, before being mutated like any other
+    pub crossover_rate: f64,
+    /// one_point or two_point
+    pub crossover: Points,
+    /// position or wires, see `crossover::Alignment`
+    pub crossover_alignment: Alignment,
+    /// How programs are scored, by name (see `fitness::from_name`), `entropy:<k>` and `coded:<k>` using a context of k bits
+    pub fitness: Arc<dyn Fitness>,
+    /// Bits of score each gate costs
+    pub complexity_penalty: i64,
+    /// How the elites are picked out of each generation, by name (see `selection::from_name`)
+    pub selection: Arc<dyn Selection>,
+    /// Gates between the wire states kept for each elite, so its children only rerun the gates after the last state they share with
+    /// it. Every state takes about as much memory as the training set, 0 keeps only the outputs
+    pub trace_every: usize,
+    /// How many programs' scores are remembered so they're never evaluated twice, 0 turning that off
+    pub cache_size: usize,
+    /// How many islands the search is split into, each breeding population children and keeping elites of its own
+    pub islands: usize,
+    /// ring or full, see `island::Topology`
+    pub migration: Topology,
+    /// Generations between migrations
+    pub migration_interval: u64,
+    /// How many of each island's best are copied to the islands it sends to every migration
+    pub migrants: usize,
+    /// Where nsga2 selection writes out the programs on its front every report, so one can be picked after the run
+    pub front_dir: Option<PathBuf>,
+    pub inp_size: usize,
+    /// Which kind of gates the programs are built from
+    pub program: Kind,
+    /// Wires per gate of arbitrairy programs, only the sizes in `with_circuit!` are available
+    pub gate_size: usize,
+    /// Stop after this many generations, or never if 0
+    pub generations: u64,
+    pub seed: Option<u64>,
+    pub report_every: u64,
+    /// Where to keep the best program found so far, written every report
+    pub save: Option<PathBuf>,
+    pub checkpoint_dir: Option<PathBuf>,
+    pub checkpoint_every: u64,
+    /// Carry on from the latest checkpoint in checkpoint_dir rather than starting afresh
+    pub resume: bool,
+    /// Files and directories to train on (comma separated), cut into inp_size blocks. Empty uses the built in counting sequences
+    pub corpus: Vec<PathBuf>,
+    /// Share of the corpus's blocks held back to validate on
+    pub validation_fraction: f64
+}
+
+impl Default for SearchConfig {
+    fn default() -> Self {
+        Self {
+            population: 500,
+            elites: 10,
+            mutation_rate: 0.1,
+            crossover_rate: 0.0,
+            crossover: Points::Two,
+            crossover_alignment: Alignment::Position,
+            fitness: Arc::new(fitness::EdgeZeros),
+            complexity_penalty: 4,
+            selection: Arc::new(selection::Truncation),
+            trace_every: 32,
+            cache_size: 100_000,
+            islands: 1,
+            migration: Topology::Ring,
+            migration_interval: 10,
+            migrants: 1,
+            front_dir: None,
+            inp_size: 400,
+            program: Kind::Arbitrairy,
+            gate_size: 4,
+            generations: 0,
+            seed: None,
+            report_every: 100,
+            save: None,
+            checkpoint_dir: None,
+            checkpoint_every: 100,
+            resume: false,
+            corpus: vec![],
+            validation_fraction: 0.0
+        }
+    }
+}
+
+impl SearchConfig {
+    /// Sets one option by name, as it'd be written in a config file
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        fn parse<T: std::str::FromStr>(key: &str, value: &str) -> Result<T, String> where T::Err: std::fmt::Display {
+            value.parse().map_err(|e| format!("Bad value `{value}` for {key}: {e}"))
+        }
+        match key {
+            "population" => self.population = parse(key, value)?,
+            "elites" => self.elites = parse(key, value)?,
+            "mutation_rate" => self.mutation_rate = parse(key, value)?,
+            "crossover_rate" => self.crossover_rate = parse(key, value)?,
+            "crossover" => self.crossover = Points::from_name(value).ok_or_else(|| format!("Unknown crossover `{value}`"))?,
+            "crossover_alignment" => self.crossover_alignment = Alignment::from_name(value).ok_or_else(|| format!("Unknown crossover alignment `{value}`"))?,
+            "fitness" => self.fitness = fitness::from_name(value).ok_or_else(|| format!("Unknown fitness `{value}`"))?,
+            "complexity_penalty" => self.complexity_penalty = parse(key, value)?,
+            "selection" => self.selection = selection::from_name(value).ok_or_else(|| format!("Unknown selection `{value}`"))?,
+            "trace_every" => self.trace_every = parse(key, value)?,
+            "cache_size" => self.cache_size = parse(key, value)?,
+            "islands" => self.islands = parse(key, value)?,
+            "migration" => self.migration = Topology::from_name(value).ok_or_else(|| format!("Unknown migration topology `{value}`"))?,
+            "migration_interval" => self.migration_interval = parse(key, value)?,
+            "migrants" => self.migrants = parse(key, value)?,
+            "front_dir" => self.front_dir = Some(PathBuf::from(value)),
+            "inp_size" => self.inp_size = parse(key, value)?,
+            "program" => self.program = Kind::from_name(value).ok_or_else(|| format!("Unknown program kind `{value}`"))?,
+            "gate_size" => self.gate_size = parse(key, value)?,
+            "generations" => self.generations = parse(key, value)?,
+            "seed" => self.seed = Some(parse(key, value)?),
+            "report_every" => self.report_every = parse(key, value)?,
+            "save" => self.save = Some(PathBuf::from(value)),
+            "checkpoint_dir" => self.checkpoint_dir = Some(PathBuf::from(value)),
+            "checkpoint_every" => self.checkpoint_every = parse(key, value)?,
+            "resume" => self.resume = parse(key, value)?,
+            "corpus" => self.corpus = value.split(',').map(|p| PathBuf::from(p.trim())).filter(|p| !p.as_os_str().is_empty()).collect(),
+            "validation_fraction" => self.validation_fraction = parse(key, value)?,
+            _ => return Err(format!("Unknown option `{key}`"))
+        }
+        Ok(())
+    }
+
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let mut config = Self::default();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = line.split_once('=').ok_or_else(|| format!("{path}:{}: Expected `key = value`", i + 1))?;
+            config.set(key.trim(), value.trim()).map_err(|e| format!("{path}:{}: {e}", i + 1))?;
+        }
+        Ok(config)
+    }
+
+    /// Builds the config from `--config <file>` (if given) then applies every other `--key value` on top, whatever order they came in.
+    /// `--resume` is the one flag that doesn't need a value
+    pub fn from_args(args: &[&str]) -> Result<Self, String> {
+        let mut config = match args.iter().position(|&a| a == "--config") {
+            Some(i) => Self::load(args.get(i + 1).ok_or("--config needs a value")?)?,
+            None => Self::default()
+        };
+        let mut args = args.iter();
+        while let Some(&flag) = args.next() {
+            let key = flag.strip_prefix("--").ok_or_else(|| format!("Expected a --flag but found `{flag}`"))?.replace('-', "_");
+            match key.as_str() {
+                "config" => { args.next(); }
+                "resume" => config.resume = true,
+                _ => config.set(&key, args.next().ok_or_else(|| format!("{flag} needs a value"))?)?
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.population == 0 || self.elites == 0 {
+            return Err("population and elites must both be at least 1".to_string());
+        }
+        if self.islands == 0 || self.migration_interval == 0 {
+            return Err("islands and migration_interval must both be at least 1".to_string());
+        }
+        if self.migrants >= self.elites && self.islands > 1 {
+            return Err(format!("migrants must be fewer than elites ({}), or migration would replace every island's elites", self.elites));
+        }
+        if !(0.0..=1.0).contains(&self.mutation_rate) {
+            return Err(format!("mutation_rate must be between 0 and 1, not {}", self.mutation_rate));
+        }
+        if !(0.0..=1.0).contains(&self.crossover_rate) {
+            return Err(format!("crossover_rate must be between 0 and 1, not {}", self.crossover_rate));
+        }
+        // Fredkins programs start out with gates on wires up to 4
+        let min_inp_size = if self.program == Kind::Fredkins { 5 } else { self.gate_size };
+        if self.inp_size < min_inp_size {
+            return Err(format!("inp_size {} is too small for {} programs (at least {min_inp_size})", self.inp_size, self.program.name()));
+        }
+        if self.report_every == 0 || self.checkpoint_every == 0 {
+            return Err("report_every and checkpoint_every must both be at least 1".to_string());
+        }
+        if !(0.0..1.0).contains(&self.validation_fraction) {
+            return Err(format!("validation_fraction must be at least 0 and below 1, not {}", self.validation_fraction));
+        }
+        if self.front_dir.is_some() && !self.selection.is_pareto() {
+            return Err("front_dir is only written with nsga2 selection".to_string());
+        }
+        if self.resume && self.checkpoint_dir.is_none() {
+            return Err("resume needs a checkpoint_dir to resume from".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Each individual gets its own rng derived from the master seed, the generation and its index in it, so children come out the same
+/// however rayon schedules them, and resuming part way through only needs the seed and generation number
+fn individual_rng(seed: u64, generation: u64, index: u64) -> StdRng {
+    // splitmix64's finalizer, so that neighbouring generations/indexes don't get related seeds
+    let mix = |mut z: u64| {
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    };
+    StdRng::seed_from_u64(mix(mix(mix(seed) ^ generation) ^ index))
+}
+
+/// Indexes of two different parents, each the better of two picked at random (out of `len` ranked best first), so pairs lean
+/// towards the best elites without always breeding the top two together
+fn choose_pair(len: usize, rng: &mut impl Rng) -> (usize, usize) {
+    let mut tournament = |exclude: Option<usize>| {
+        let mut pick = || loop {
+            let i = rng.random_range(0..len);
+            if Some(i) != exclude {
+                return i;
+            }
+        };
+        pick().min(pick())
+    };
+    let first = tournament(None);
+    let second = tournament(Some(first));
+    (first, second)
+}
+
+/// Evolutionary search over programs, scored on the corpus's training blocks
+pub struct Search<C: ReversibleCircuit> {
+    config: SearchConfig,
+    corpus: Corpus,
+    seed: u64,
+    generation: u64,
+    islands: Vec<Island<C>>,
+    /// The training set packed for tracing
+    start: Sliced,
+    cache: FitnessCache,
+    /// What the training set and the way programs are scored go by in the cache
+    train_id: u64,
+    fitness_id: u64,
+    /// Best score (over all islands) of every generation so far
+    history: Vec<i64>,
+    /// First front out of everything the last generation evaluated (on every island), only kept for pareto selection
+    front: Vec<(C, Objectives)>
+}
+
+impl<C: ReversibleCircuit> Search<C> {
+    pub fn new(config: SearchConfig, corpus: Corpus) -> Self {
+        Self {
+            seed: config.seed.unwrap_or_else(rand::random),
+            islands: (0..config.islands).map(|_| Island::new(vec![C::new(config.inp_size)])).collect(),
+            start: Sliced::pack::<C>(&corpus.train, config.inp_size),
+            cache: FitnessCache::new(config.cache_size),
+            train_id: cache::id_of(&corpus.train),
+            // Variance only gets worked out for pareto selection, so that's part of how programs were scored too
+            fitness_id: cache::id_of(&(config.fitness.name(), config.selection.is_pareto())),
+            config,
+            corpus,
+            generation: 0,
+            history: vec![],
+            front: vec![]
+        }
+    }
+
+    /// Picks up from the latest checkpoint in the configured directory, returning whether there was one
+    pub fn resume(&mut self) -> io::Result<bool> {
+        let Some(checkpoint) = self.config.checkpoint_dir.as_deref().map(checkpoint::load_latest).transpose()?.flatten() else {
+            return Ok(false);
+        };
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        if checkpoint.islands.len() != self.config.islands {
+            return Err(invalid(format!("Checkpoint has {} islands rather than {}", checkpoint.islands.len(), self.config.islands)));
+        }
+        let mut population: Vec<C> = checkpoint.population.iter().map(|p| C::from_bytes(p)).try_collect()?;
+        if population.iter().any(|p| p.inp_size() != self.config.inp_size) {
+            return Err(invalid(format!("Checkpoint is for a different inp_size than {}", self.config.inp_size)));
+        }
+        self.islands = checkpoint.islands.iter().map(|&size| Island::new(population.drain(..size as usize).collect())).collect();
+        (self.generation, self.seed, self.history) = (checkpoint.generation, checkpoint.seed, checkpoint.history);
+        Ok(true)
+    }
+
+    /// Objectives given the program's outputs on the training set. Only pareto selection looks at the variance, so it's left at 0
+    /// otherwise rather than scoring every block separately
+    fn objectives_of(&self, program: &C, outputs: &[BitVec]) -> Objectives {
+        let train = &self.corpus.train;
+        let variance = if self.config.selection.is_pareto() {
+            Objectives::variance_of(&train.iter().zip(outputs).map(|(t, o)| self.config.fitness.score_block(t, o)).collect_vec())
+        } else {
+            0.0
+        };
+        Objectives { saved: self.config.fitness.score(train, outputs), gates: program.complexity(), variance }
+    }
+
+    fn scalarise(&self, objectives: &Objectives) -> i64 {
+        objectives.saved - objectives.gates * self.config.complexity_penalty
+    }
+
+    /// Breeds the next generation on every island and keeps each island's elites, returning every score it saw (each island's
+    /// children then parents, island after island), then migrates if it's time to. Deterministic given the seed and generation
+    pub fn step(&mut self) -> Vec<i64> {
+        for island in self.islands.iter_mut().filter(|island| island.traces.len() != island.bests.len()) {
+            island.traces = island.bests.par_iter().map(|p| Trace::new(p, &self.start, self.config.trace_every)).collect();
+        }
+        let islands = std::mem::take(&mut self.islands);
+        let population = self.config.population;
+        // Each island's individuals get rngs of their own, numbered on from the last island's (selection included)
+        let rng_index = |island: usize, index: usize| (island * (population + 1) + index) as u64;
+        // Every child remembers which elite it got its first gates from, so it can be run on from that elite's trace
+        let children = (0..islands.len() * population).into_par_iter().map(|n| {
+            let (island, index) = (n / population, n % population);
+            let bests = &islands[island].bests;
+            let mut rng = individual_rng(self.seed, self.generation, rng_index(island, index));
+            if self.config.crossover_rate > 0.0 && bests.len() > 1 && rng.random_bool(self.config.crossover_rate) {
+                let (a, b) = choose_pair(bests.len(), &mut rng);
+                let child = bests[a].crossover(&bests[b], Crossover { points: self.config.crossover, alignment: self.config.crossover_alignment }, &mut rng);
+                (child.mutate(self.config.mutation_rate, &mut rng), a)
+            } else {
+                let parent = rng.random_range(0..bests.len());
+                (bests[parent].mutate(self.config.mutation_rate, &mut rng), parent)
+            }
+        }).collect::<Vec<_>>();
+        // Every island's children then its elites, as (island, index) with the elites numbered on from the children
+        let candidates = islands.iter().enumerate().flat_map(|(i, island)| (0..population + island.bests.len()).map(move |j| (i, j))).collect_vec();
+        let program = |(i, j): (usize, usize)| if j < population { &children[i * population + j].0 } else { &islands[i].bests[j - population] };
+
+        // Only programs that aren't cached get evaluated, and only once each however many copies of them there are (on any island)
+        let keys = candidates.iter().map(|&c| Key::new(program(c), self.train_id, self.fitness_id)).collect_vec();
+        let cached = keys.iter().map(|key| self.cache.get(key)).collect_vec();
+        let mut seen = HashSet::new();
+        let missing = (0..keys.len()).filter(|&n| cached[n].is_none() && seen.insert(keys[n])).collect_vec();
+        let fresh: HashMap<Key, Objectives> = missing.par_iter().map(|&n| {
+            let (i, j) = candidates[n];
+            let island = &islands[i];
+            (keys[n], match j.checked_sub(population) {
+                None => {
+                    let (child, parent) = &children[i * population + j];
+                    self.objectives_of(child, &island.traces[*parent].outputs_of(&island.bests[*parent], child, &self.start))
+                }
+                Some(elite) => self.objectives_of(&island.bests[elite], &island.traces[elite].outputs())
+            })
+        }).collect();
+        fresh.iter().for_each(|(&key, &objectives)| self.cache.insert(key, objectives));
+        let evaluated = keys.iter().zip(cached).map(|(key, cached)| cached.unwrap_or_else(|| fresh[key])).collect_vec();
+        let all_scores = evaluated.iter().map(|o| self.scalarise(o)).collect_vec();
+        // Copies of one program all land on the same front at no distance from each other, so pareto selection (and the front) only
+        // sees each program once, the first time it comes up
+        let is_pareto = self.config.selection.is_pareto();
+        let distinct = |candidates: Range<usize>| {
+            let mut seen = HashSet::new();
+            candidates.filter(|&n| !is_pareto || seen.insert(keys[n])).collect_vec()
+        };
+        if is_pareto {
+            let everything = distinct(0..keys.len());
+            let objectives = everything.iter().map(|&n| evaluated[n]).collect_vec();
+            self.front = pareto::fronts(&objectives).swap_remove(0).into_iter()
+                .map(|k| (program(candidates[everything[k]]).clone(), objectives[k]))
+                .collect();
+        }
+
+        // Each island selects out of its own candidates only
+        let ranges = islands.iter().scan(0, |offset, island| {
+            let range = *offset..*offset + population + island.bests.len();
+            *offset = range.end;
+            Some(range)
+        }).collect_vec();
+        self.islands = islands.par_iter().zip(ranges).enumerate().map(|(i, (island, range))| {
+            let scores = &all_scores[range.clone()];
+            let choices = distinct(range.clone());
+            // Selection gets an rng of its own, as if it were one more individual after the island's children
+            let mut rng = individual_rng(self.seed, self.generation, rng_index(i, population));
+            let survivors = self.config.selection.select(
+                &choices.iter().map(|&n| all_scores[n]).collect_vec(),
+                &choices.iter().map(|&n| evaluated[n]).collect_vec(),
+                self.config.elites,
+                &mut rng
+            ).into_iter().map(|k| choices[k] - range.start).collect_vec();
+            let (bests, traces) = survivors.par_iter().map(|&j| match j.checked_sub(population) {
+                None => {
+                    let (child, parent) = &children[i * population + j];
+                    (child.clone(), island.traces[*parent].retrace(&island.bests[*parent], child, &self.start))
+                }
+                Some(elite) => (island.bests[elite].clone(), island.traces[elite].clone())
+            }).unzip();
+            Island { bests, traces, scores: survivors.iter().map(|&j| scores[j]).collect() }
+        }).collect();
+        self.history.push(self.islands.iter().map(|island| island.scores[0]).max().unwrap());
+        self.generation += 1;
+        if self.islands.len() > 1 && self.generation.is_multiple_of(self.config.migration_interval) {
+            island::migrate(&mut self.islands, self.config.migration, self.config.migrants);
+        }
+        all_scores
+    }
+
+    /// Steps until the configured number of generations, reporting, saving and checkpointing along the way
+    pub fn run(&mut self) -> io::Result<()> {
+        println!("Seed {}, scoring by {}, selecting by {}", self.seed, self.config.fitness.name(), self.config.selection.name());
+        if self.islands.len() > 1 {
+            println!("{} islands, migrating {} each every {} generations ({})", self.islands.len(), self.config.migrants, self.config.migration_interval, self.config.migration.name());
+        }
+        while self.config.generations == 0 || self.generation < self.config.generations {
+            let reporting = self.generation.is_multiple_of(self.config.report_every);
+            let scores = self.step();
+            if reporting {
+                self.report(&scores)?;
+            }
+            if let Some(dir) = &self.config.checkpoint_dir && self.generation.is_multiple_of(self.config.checkpoint_every) {
+                checkpoint::save(dir, &self.checkpoint())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn report(&self, scores: &[i64]) -> io::Result<()> {
+        let best = self.best();
+        let train = &self.corpus.train;
+        println!("{:?}", scores);
+        // Only the first few per-block scores, a real corpus can have thousands of blocks
+        println!("Best {}, did {} (namely {}), got:\n{}",
+                 best.complexity(),
+                 fitness::evaluate(&*self.config.fitness, best, train),
+                 train.iter().take(256).map(|t| self.config.fitness.score_block(t, &best.forward(t.clone()))).join(","),
+                 best.forward(train[0].clone())
+        );
+        if self.islands.len() > 1 {
+            for (i, island) in self.islands.iter().enumerate() {
+                println!("Island {i}: {}", island.stats());
+            }
+        }
+        if self.config.cache_size > 0 {
+            println!("Fitness cache: {} of {} lookups hit, holding {}", self.cache.hits, self.cache.lookups, self.cache.len());
+        }
+        if !self.corpus.validation.is_empty() {
+            println!("Validation: did {} over {} blocks", fitness::evaluate(&*self.config.fitness, best, &self.corpus.validation), self.corpus.validation.len());
+        }
+        if let Some(path) = &self.config.save {
+            best.save(path)?;
+        }
+        if self.config.selection.is_pareto() {
+            self.report_front()?;
+        }
+        Ok(())
+    }
+
+    /// Lists the programs on the last generation's first front (children and elites of every island), writing them to front_dir if
+    /// there is one
+    fn report_front(&self) -> io::Result<()> {
+        let front = self.front.iter().sorted_by_key(|(_, o)| o.gates).collect_vec();
+        println!("Front of {}:", front.len());
+        for (_, o) in &front {
+            println!("    saved {}, {} gates, variance {:.1}", o.saved, o.gates, o.variance);
+        }
+        let Some(dir) = &self.config.front_dir else { return Ok(()) };
+        fs::create_dir_all(dir)?;
+        // Clear out the last front, which may have had more members
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.starts_with("front-") && n.ends_with(".txt")) {
+                fs::remove_file(path)?;
+            }
+        }
+        let mut summary = "file\tsaved\tgates\tvariance\n".to_string();
+        for (n, (program, o)) in front.iter().enumerate() {
+            let name = format!("front-{n}.txt");
+            program.save(&dir.join(&name))?;
+            summary += &format!("{name}\t{}\t{}\t{}\n", o.saved, o.gates, o.variance);
+        }
+        fs::write(dir.join("front.tsv"), summary)
+    }
+
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            generation: self.generation,
+            seed: self.seed,
+            history: self.history.clone(),
+            population: self.islands.iter().flat_map(|island| &island.bests).map(C::to_bytes).collect(),
+            islands: self.islands.iter().map(|island| island.bests.len() as u64).collect()
+        }
+    }
+
+    /// The best of the islands' bests, or the first island's before there are any scores
+    pub fn best(&self) -> &C {
+        &self.islands.iter().min_by_key(|island| island.scores.first().map_or(i64::MAX, |s| -s)).unwrap().bests[0]
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bit_vec::BitVec;
+
+    use super::*;
+    use crate::arbitrairy_program::Program;
+
+    fn corpus() -> Corpus {
+        Corpus { train: (0..16u8).map(|s| BitVec::from_bytes(&[s, s + 1, s + 2])).collect(), validation: vec![] }
+    }
+
+    /// Two runs from the same seed have to breed exactly the same programs, whatever order rayon happens to run things in
+    #[test]
+    fn only_depends_on_the_seed() {
+        for (name, islands, migration) in [("truncation", 1, Topology::Ring), ("tournament:3", 3, Topology::Ring), ("roulette", 1, Topology::Ring), ("rank", 3, Topology::Full), ("nsga2", 2, Topology::Full)] {
+            let selection = selection::from_name(name).unwrap();
+            let config = SearchConfig {
+                population: 100, inp_size: 64, seed: Some(7), crossover_rate: 0.5, selection, islands, migration, migration_interval: 2, migrants: 3,
+                ..SearchConfig::default()
+            };
+            let run = |config: &SearchConfig| {
+                let mut search = Search::<Program<4, 16>>::new(config.clone(), corpus());
+                (0..3).map(|_| (search.step(), search.best().to_bytes())).collect_vec()
+            };
+            let first = run(&config);
+            assert!(run(&config) == first, "Same seed gave different searches with {name} selection on {islands} islands");
+            // Whether scores come out of the cache (or got evicted from a tiny one) can't change anything
+            assert!(run(&SearchConfig { cache_size: 0, ..config.clone() }) == first, "The fitness cache changed the search with {name} selection");
+            assert!(run(&SearchConfig { cache_size: 3, ..config.clone() }) == first, "The fitness cache changed the search with {name} selection");
+            assert!(run(&SearchConfig { seed: Some(8), ..config }) != first, "Different seeds gave the same search");
+        }
+    }
+
+    /// Mutating so little that children keep coming out the same as their parents, which mustn't fill up the elites or the front
+    #[test]
+    fn nsga2_keeps_each_program_once() {
+        let config = SearchConfig {
+            population: 50, elites: 10, inp_size: 64, seed: Some(7), mutation_rate: 0.001, selection: selection::from_name("nsga2").unwrap(),
+            islands: 2, migration_interval: 2, migrants: 3,
+            ..SearchConfig::default()
+        };
+        let mut search = Search::<Program<4, 16>>::new(config, corpus());
+        for _ in 0..6 {
+            search.step();
+            for island in &search.islands {
+                assert!(island.bests.iter().map(|p| p.structural_hash()).all_unique(), "Kept copies: {}", island.stats());
+            }
+            assert!(!search.front.is_empty() && search.front.iter().map(|(p, _)| p.structural_hash()).all_unique());
+            assert!(search.front.iter().all(|(_, o)| !search.front.iter().any(|(_, other)| other.dominates(o))));
+        }
+    }
+
+    /// Stopping, checkpointing and picking up again in a fresh search has to carry on exactly as if the run had never stopped
+    #[test]
+    fn resumes_where_it_left_off() {
+        for (islands, selection) in [(1, "truncation"), (3, "nsga2")] {
+            let dir = std::env::temp_dir().join(format!("search-resume-test-{}-{islands}", std::process::id()));
+            let config = SearchConfig {
+                population: 40, inp_size: 64, seed: Some(7), crossover_rate: 0.5, selection: selection::from_name(selection).unwrap(), islands,
+                migration_interval: 2, migrants: 2, checkpoint_dir: Some(dir.clone()),
+                ..SearchConfig::default()
+            };
+            let mut uninterrupted = Search::<Program<4, 16>>::new(config.clone(), corpus());
+            (0..7).for_each(|_| { uninterrupted.step(); });
+
+            let mut first = Search::<Program<4, 16>>::new(config.clone(), corpus());
+            (0..3).for_each(|_| { first.step(); });
+            checkpoint::save(&dir, &first.checkpoint()).unwrap();
+            let mut resumed = Search::<Program<4, 16>>::new(SearchConfig { seed: Some(8), ..config }, corpus());
+            assert!(resumed.resume().unwrap());
+            (0..4).for_each(|_| { resumed.step(); });
+            fs::remove_dir_all(&dir).unwrap();
+
+            assert_eq!(resumed.best().to_bytes(), uninterrupted.best().to_bytes(), "Resuming changed the best on {islands} islands");
+            assert_eq!(resumed.history, uninterrupted.history);
+            assert_eq!(resumed.checkpoint().population, uninterrupted.checkpoint().population);
+        }
+    }
+
+    #[test]
+    fn flags_override_the_config_file() {
+        let path = std::env::temp_dir().join(format!("search-config-test-{}", std::process::id()));
+        fs::write(&path, "# Smaller than usual\npopulation = 30  # children\n\nelites = 3\n").unwrap();
+        let config = SearchConfig::from_args(&["--population", "40", "--config", path.to_str().unwrap(), "--mutation-rate", "0.5"]).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!((config.population, config.elites, config.mutation_rate), (40, 3, 0.5));
+    }
+
+    #[test]
+    fn refuses_bad_options() {
+        let config = SearchConfig::from_args(&["--resume", "--checkpoint-dir", "checkpoints", "--seed", "3"]).unwrap();
+        assert!(config.resume && config.seed == Some(3));
+        for args in [
+            &["--population", "0"][..],
+            &["--mutation-rate", "1.5"],
+            &["--inp-size", "3"],
+            &["--validation-fraction", "1"],
+            &["--crossover-rate", "-0.1"],
+            &["--crossover", "three_point"],
+            &["--crossover-alignment", "gates"],
+            &["--fitness", "zeros"],
+            &["--fitness", "entropy:21"],
+            &["--selection", "tournament:0"],
+            &["--front-dir", "front"],
+            &["--islands", "0"],
+            &["--islands", "2", "--elites", "4", "--migrants", "4"],
+            &["--migration", "star"],
+            &["--migration-interval", "0"],
+            &["--resume"],
+            &["--elites"],
+            &["--elites", "many"],
+            &["--colour", "blue"],
+            &["population", "30"]
+        ] {
+            assert!(SearchConfig::from_args(args).is_err(), "Accepted {args:?}");
+        }
+    }
+
+    #[test]
+    fn pairs_are_distinct_and_lean_towards_the_best() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut firsts = [0; 10];
+        for _ in 0..1000 {
+            let (a, b) = choose_pair(10, &mut rng);
+            assert_ne!(a, b);
+            firsts[a] += 1;
+        }
+        assert!(firsts[0] > firsts[9] * 5, "Picked {firsts:?}");
+        let (a, b) = choose_pair(2, &mut rng);
+        assert_eq!(a + b, 1);
+    }
+}
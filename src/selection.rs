@@ -0,0 +1,224 @@
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use itertools::Itertools;
+use rand::rngs::StdRng;
+use rand::Rng;
+
+use crate::pareto::{self, Objectives};
+
+/// How the survivors of each generation are picked out of the children and the previous survivors
+pub trait Selection: Debug + Send + Sync {
+    /// What it's called in configs
+    fn name(&self) -> String;
+    /// Picks `n` different candidates (or all of them if there aren't that many) by index, best first. `scores` is bits saved less the
+    /// complexity penalty, `objectives` has the same candidates' separate objectives
+    fn select(&self, scores: &[i64], objectives: &[Objectives], n: usize, rng: &mut StdRng) -> Vec<usize>;
+    /// Whether this looks at whole `Objectives` rather than just the scores, so they need working out in full and there's a front to report
+    fn is_pareto(&self) -> bool { false }
+}
+
+/// Every selection there is, by the name it's given in configs. `tournament:<k>` holds tournaments of k, plain `tournament` of 2
+pub fn from_name(name: &str) -> Option<Arc<dyn Selection>> {
+    Some(match name {
+        "truncation" => Arc::new(Truncation),
+        "tournament" => Arc::new(Tournament { size: 2 }),
+        "roulette" => Arc::new(Roulette),
+        "rank" => Arc::new(Rank),
+        "nsga2" => Arc::new(Nsga2),
+        _ => Arc::new(Tournament { size: name.strip_prefix("tournament:")?.parse().ok().filter(|&k| k > 0)? })
+    })
+}
+
+fn best_first(scores: &[i64], picked: Vec<usize>) -> Vec<usize> {
+    picked.into_iter().sorted_by_key(|&i| (-scores[i], i)).collect()
+}
+
+/// Always keeps the single best, so the best program found so far is never lost, then fills up the rest with whatever `pick`
+/// chooses out of those not yet picked (given as indexes into `scores`, returning an index into that list)
+fn keep_best_then(scores: &[i64], n: usize, mut pick: impl FnMut(&[usize]) -> usize) -> Vec<usize> {
+    let mut remaining = (0..scores.len()).collect_vec();
+    let Some(best) = remaining.iter().position_min_by_key(|&&i| -scores[i]) else { return vec![] };
+    let mut picked = vec![remaining.remove(best)];
+    while picked.len() < n && !remaining.is_empty() {
+        let i = pick(&remaining);
+        picked.push(remaining.remove(i));
+    }
+    best_first(scores, picked)
+}
+
+/// Index into `weights` picked with probability proportional to its weight
+fn spin(weights: &[f64], rng: &mut StdRng) -> usize {
+    let total: f64 = weights.iter().sum();
+    let mut target = rng.random_range(0.0..total);
+    for (i, w) in weights.iter().enumerate() {
+        if target < *w {
+            return i;
+        }
+        target -= w;
+    }
+    weights.len() - 1
+}
+
+/// Just the best n
+#[derive(Debug)]
+pub struct Truncation;
+
+impl Selection for Truncation {
+    fn name(&self) -> String { "truncation".to_string() }
+
+    fn select(&self, scores: &[i64], _: &[Objectives], n: usize, _: &mut StdRng) -> Vec<usize> {
+        best_first(scores, (0..scores.len()).collect()).into_iter().take(n).collect()
+    }
+}
+
+/// Each survivor is the best of `size` picked at random (from those not already picked)
+#[derive(Debug)]
+pub struct Tournament {
+    pub size: usize
+}
+
+impl Selection for Tournament {
+    fn name(&self) -> String {
+        if self.size == 2 { "tournament".to_string() } else { format!("tournament:{}", self.size) }
+    }
+
+    fn select(&self, scores: &[i64], _: &[Objectives], n: usize, rng: &mut StdRng) -> Vec<usize> {
+        keep_best_then(scores, n, |remaining| {
+            (0..self.size).map(|_| rng.random_range(0..remaining.len())).min_by_key(|&i| (-scores[remaining[i]], i)).unwrap()
+        })
+    }
+}
+
+/// Chance of surviving in proportion to how far a score is above the worst (plus one, so the worst still has a chance)
+#[derive(Debug)]
+pub struct Roulette;
+
+impl Selection for Roulette {
+    fn name(&self) -> String { "roulette".to_string() }
+
+    fn select(&self, scores: &[i64], _: &[Objectives], n: usize, rng: &mut StdRng) -> Vec<usize> {
+        let Some(&worst) = scores.iter().min() else { return vec![] };
+        keep_best_then(scores, n, |remaining| {
+            spin(&remaining.iter().map(|&i| (scores[i] - worst + 1) as f64).collect_vec(), rng)
+        })
+    }
+}
+
+/// Like roulette but by position in the ranking rather than score, so one far better program doesn't crowd out everything else
+#[derive(Debug)]
+pub struct Rank;
+
+impl Selection for Rank {
+    fn name(&self) -> String { "rank".to_string() }
+
+    fn select(&self, scores: &[i64], _: &[Objectives], n: usize, rng: &mut StdRng) -> Vec<usize> {
+        // The worst gets 1 and the best scores.len(), ties sharing the lower rank
+        let sorted = scores.iter().copied().sorted().collect_vec();
+        let ranks = scores.iter().map(|&s| (sorted.partition_point(|&t| t < s) + 1) as f64).collect_vec();
+        keep_best_then(scores, n, |remaining| spin(&remaining.iter().map(|&i| ranks[i]).collect_vec(), rng))
+    }
+}
+
+/// The best non-dominated fronts over bits saved, gate count and per-block variance, see `pareto`. The score then only decides which
+/// of the first front counts as the best
+#[derive(Debug)]
+pub struct Nsga2;
+
+impl Selection for Nsga2 {
+    fn name(&self) -> String { "nsga2".to_string() }
+
+    fn select(&self, scores: &[i64], objectives: &[Objectives], n: usize, _: &mut StdRng) -> Vec<usize> {
+        let Some(&top) = scores.iter().max().filter(|_| n > 0) else { return vec![] };
+        let mut picked = pareto::select(objectives, n).into_iter().flat_map(|front| best_first(scores, front)).collect_vec();
+        // Programs can tie on the best score and differ in variance, so the best isn't necessarily on the first front, and even if it
+        // is crowding might drop it when that front's too big to keep whole. Then the least dominated of them takes the place of the
+        // last (most crowded) pick
+        let best = match picked.iter().position(|&i| scores[i] == top) {
+            Some(i) => picked.remove(i),
+            None => {
+                picked.pop();
+                let dominated = |i: usize| objectives.iter().filter(|o| o.dominates(&objectives[i])).count();
+                (0..scores.len()).filter(|&i| scores[i] == top).min_by_key(|&i| (dominated(i), i)).unwrap()
+            }
+        };
+        picked.insert(0, best);
+        picked
+    }
+
+    fn is_pareto(&self) -> bool { true }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    const NAMES: [&str; 6] = ["truncation", "tournament", "tournament:5", "roulette", "rank", "nsga2"];
+
+    #[test]
+    fn found_by_name() {
+        for name in NAMES {
+            assert_eq!(from_name(name).unwrap().name(), name);
+        }
+        assert_eq!(from_name("tournament:2").unwrap().name(), "tournament");
+        for name in ["tournament:0", "tournament:", "best"] {
+            assert!(from_name(name).is_none(), "Found {name}");
+        }
+    }
+
+    /// Every selection has to pick the right number of different candidates, best first and always including the very best
+    #[test]
+    fn keeps_the_best_and_picks_each_once() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..200 {
+            let scores: Vec<i64> = (0..rng.random_range(1..=60)).map(|_| rng.random_range(-50..50)).collect();
+            let objectives: Vec<Objectives> = scores.iter().map(|&saved| Objectives { saved, gates: rng.random_range(0..10), variance: 0.0 }).collect();
+            let n = rng.random_range(1..=60);
+            for name in NAMES {
+                let selection = from_name(name).unwrap();
+                let picked = selection.select(&scores, &objectives, n, &mut rng);
+                assert_eq!(picked.len(), n.min(scores.len()), "{name} picked the wrong number");
+                assert!(picked.iter().all_unique(), "{name} picked something twice");
+                assert_eq!(scores[picked[0]], *scores.iter().max().unwrap(), "{name} lost the best");
+                if !selection.is_pareto() {
+                    assert!(picked.windows(2).all(|w| scores[w[0]] >= scores[w[1]]), "{name} didn't put the best first");
+                }
+            }
+        }
+        assert!(Roulette.select(&[], &[], 3, &mut rng).is_empty());
+    }
+
+    /// Past the best, the rest are drawn at random, but better scores have to come up more often
+    #[test]
+    fn leans_towards_better_scores() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let scores = (0..10).collect_vec();
+        let objectives = scores.iter().map(|&saved| Objectives { saved, gates: 0, variance: 0.0 }).collect_vec();
+        for name in ["tournament", "roulette", "rank"] {
+            let selection = from_name(name).unwrap();
+            let mut kept = [0; 10];
+            for _ in 0..1000 {
+                for i in selection.select(&scores, &objectives, 3, &mut rng) {
+                    kept[i] += 1;
+                }
+            }
+            assert_eq!(kept[9], 1000);
+            assert!(kept[8] > kept[1] && kept[1] > 0, "{name} kept {kept:?}");
+        }
+    }
+
+    /// Fronts are over every candidate, so one only the best dominates doesn't get promoted, and a tie on score goes to whichever
+    /// isn't dominated
+    #[test]
+    fn nsga2_fronts_include_the_best() {
+        let objectives = [(10, 1, 5.0), (10, 1, 1.0), (9, 1, 1.0), (5, 0, 0.0)].map(|(saved, gates, variance)| Objectives { saved, gates, variance });
+        let scores = objectives.map(|o| o.saved - o.gates);
+        let select = |n| Nsga2.select(&scores, &objectives, n, &mut StdRng::seed_from_u64(0));
+        assert_eq!(select(1), vec![1]);
+        assert_eq!(select(2), vec![1, 3]);
+        assert_eq!(select(3), vec![1, 3, 0]);
+        assert!(select(0).is_empty());
+    }
+}
@@ -0,0 +1,326 @@
+//! Shared pieces of the on-disk program formats. Each program type writes its own gates, this handles everything around them.
+//!
+//! Binary layout: MAGIC, VERSION (1 byte), kind (1 byte), gate size (1 byte, 0 for fredkins), inp_size (u64 LE), gate count (u64 LE),
+//! then the gates, each as its wires (u32 LE each) followed by, for arbitrairy programs, its SBox permutation (u16 LE each).
+//!
+//! Text layout is one `key value` pair per line, `#` starting a comment:
+//! ```text
+//! reversible-program 1
+//! kind arbitrairy
+//! gate_size 4
+//! inp_size 400
+//! gate 0 1 2 3 | 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15
+//! ```
+//! where fredkins gates are just `gate switch g1 g2`
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use itertools::Itertools;
+
+pub const MAGIC: [u8; 4] = *b"RVPG";
+pub const VERSION: u8 = 1;
+const TEXT_MAGIC: &str = "reversible-program";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Fredkins,
+    Arbitrairy
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Fredkins => "fredkins",
+            Kind::Arbitrairy => "arbitrairy"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Kind::Fredkins, Kind::Arbitrairy].into_iter().find(|k| k.name() == name)
+    }
+
+    fn tag(self) -> u8 {
+        match self {
+            Kind::Fredkins => 0,
+            Kind::Arbitrairy => 1
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum FormatError {
+    BadMagic,
+    UnsupportedVersion(u64),
+    WrongKind { expected: Kind, found: String },
+    WrongGateSize { expected: usize, found: usize },
+    Truncated,
+    TrailingData,
+    /// A line of the text form that couldn't be understood
+    BadLine { line: usize, reason: String },
+    /// A gate that parsed but would break reversibility (repeated/out of range wires, non-permutation SBox)
+    InvalidGate { gate: usize, reason: String },
+    MissingField(&'static str),
+    NoGates
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::BadMagic => write!(f, "Not a saved program"),
+            FormatError::UnsupportedVersion(v) => write!(f, "Unsupported program format version {v} (expected {VERSION})"),
+            FormatError::WrongKind { expected, found } => write!(f, "Expected a {} program but found {found}", expected.name()),
+            FormatError::WrongGateSize { expected, found } => write!(f, "Expected gates of size {expected} but found {found}"),
+            FormatError::Truncated => write!(f, "Program is truncated"),
+            FormatError::TrailingData => write!(f, "Unexpected data after the last gate"),
+            FormatError::BadLine { line, reason } => write!(f, "Line {line}: {reason}"),
+            FormatError::InvalidGate { gate, reason } => write!(f, "Gate {gate}: {reason}"),
+            FormatError::MissingField(field) => write!(f, "Missing `{field}`"),
+            FormatError::NoGates => write!(f, "Program has no gates")
+        }
+    }
+}
+
+impl std::error::Error for FormatError {}
+
+impl From<FormatError> for io::Error {
+    fn from(e: FormatError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// What precedes the gates, in both forms
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub kind: Kind,
+    pub gate_size: usize,
+    pub inp_size: usize,
+    pub gates: usize
+}
+
+pub fn write_header(out: &mut Vec<u8>, header: Header) {
+    out.extend_from_slice(&MAGIC);
+    out.extend([VERSION, header.kind.tag(), header.gate_size as u8]);
+    out.extend_from_slice(&(header.inp_size as u64).to_le_bytes());
+    out.extend_from_slice(&(header.gates as u64).to_le_bytes());
+}
+
+/// Reads the header and checks it describes the kind (and gate size) of program the caller is expecting
+pub fn read_header(reader: &mut ByteReader, kind: Kind, gate_size: usize) -> Result<Header, FormatError> {
+    if reader.take(4)? != MAGIC {
+        return Err(FormatError::BadMagic);
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(FormatError::UnsupportedVersion(version as u64));
+    }
+    let tag = reader.u8()?;
+    if tag != kind.tag() {
+        return Err(FormatError::WrongKind { expected: kind, found: format!("kind tag {tag}") });
+    }
+    let found_gate_size = reader.u8()? as usize;
+    if found_gate_size != gate_size {
+        return Err(FormatError::WrongGateSize { expected: gate_size, found: found_gate_size });
+    }
+    let inp_size = reader.u64()? as usize;
+    let gates = reader.u64()? as usize;
+    if gates == 0 {
+        return Err(FormatError::NoGates);
+    }
+    Ok(Header { kind, gate_size, inp_size, gates })
+}
+
+/// The kind and gate size of a saved program in either form, without parsing the rest of it. Needed to pick which type to load it as
+pub fn peek(bytes: &[u8]) -> Option<(Kind, usize)> {
+    if bytes.starts_with(&MAGIC) {
+        let kind = [Kind::Fredkins, Kind::Arbitrairy].into_iter().find(|k| Some(&k.tag()) == bytes.get(5))?;
+        return Some((kind, *bytes.get(6)? as usize));
+    }
+    let text = String::from_utf8_lossy(bytes);
+    let field = |key: &str| text.lines().find_map(|l| {
+        let (k, v) = l.split('#').next().unwrap().trim().split_once(char::is_whitespace)?;
+        (k == key).then(|| v.trim().to_string())
+    });
+    let kind = Kind::from_name(&field("kind")?)?;
+    let gate_size = match kind {
+        Kind::Fredkins => 0,
+        Kind::Arbitrairy => field("gate_size")?.parse().ok()?
+    };
+    Some((kind, gate_size))
+}
+
+pub struct ByteReader<'a> {
+    bytes: &'a [u8]
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+        if self.bytes.len() < n {
+            return Err(FormatError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, FormatError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, FormatError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, FormatError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Call once everything has been read, to reject anything tacked on the end
+    pub fn finish(self) -> Result<(), FormatError> {
+        if self.bytes.is_empty() { Ok(()) } else { Err(FormatError::TrailingData) }
+    }
+}
+
+pub fn write_text_header(header: Header) -> String {
+    let mut text = format!("{TEXT_MAGIC} {VERSION}\nkind {}\n", header.kind.name());
+    if header.kind == Kind::Arbitrairy {
+        text += &format!("gate_size {}\n", header.gate_size);
+    }
+    text + &format!("inp_size {}\n", header.inp_size)
+}
+
+/// (line number, contents) of each gate line in the text form
+pub type GateLines<'a> = Vec<(usize, &'a str)>;
+
+/// Splits the text form into its header and its gate lines, checking the header matches what the caller expects
+pub fn read_text(text: &str, kind: Kind, gate_size: usize) -> Result<(Header, GateLines<'_>), FormatError> {
+    let mut lines = text.lines().enumerate()
+        .map(|(i, l)| (i + 1, l.split('#').next().unwrap().trim()))
+        .filter(|(_, l)| !l.is_empty());
+    let (line, first) = lines.next().ok_or(FormatError::BadMagic)?;
+    let version = match first.split_once(' ') {
+        Some((TEXT_MAGIC, version)) => parse_one::<u64>(version, line)?,
+        _ => return Err(FormatError::BadMagic)
+    };
+    if version != VERSION as u64 {
+        return Err(FormatError::UnsupportedVersion(version));
+    }
+
+    let (mut found_kind, mut found_gate_size, mut inp_size, mut gates) = (None, None, None, vec![]);
+    for (line, contents) in lines {
+        let (key, value) = contents.split_once(char::is_whitespace).unwrap_or((contents, ""));
+        match key {
+            "kind" => found_kind = Some(value.trim()),
+            "gate_size" => found_gate_size = Some(parse_one::<usize>(value, line)?),
+            "inp_size" => inp_size = Some(parse_one::<usize>(value, line)?),
+            "gate" => gates.push((line, value)),
+            _ => return Err(FormatError::BadLine { line, reason: format!("Unknown key `{key}`") })
+        }
+    }
+
+    let found_kind = found_kind.ok_or(FormatError::MissingField("kind"))?;
+    if found_kind != kind.name() {
+        return Err(FormatError::WrongKind { expected: kind, found: found_kind.to_string() });
+    }
+    let found_gate_size = match kind {
+        Kind::Fredkins => 0,
+        Kind::Arbitrairy => found_gate_size.ok_or(FormatError::MissingField("gate_size"))?
+    };
+    if found_gate_size != gate_size {
+        return Err(FormatError::WrongGateSize { expected: gate_size, found: found_gate_size });
+    }
+    let inp_size = inp_size.ok_or(FormatError::MissingField("inp_size"))?;
+    if gates.is_empty() {
+        return Err(FormatError::NoGates);
+    }
+    Ok((Header { kind, gate_size, inp_size, gates: gates.len() }, gates))
+}
+
+/// Parses every whitespace separated number in `s`
+pub fn parse_all<T: FromStr>(s: &str, line: usize) -> Result<Vec<T>, FormatError> where T::Err: fmt::Display {
+    s.split_whitespace().map(|n| parse_one(n, line)).collect()
+}
+
+fn parse_one<T: FromStr>(s: &str, line: usize) -> Result<T, FormatError> where T::Err: fmt::Display {
+    s.trim().parse().map_err(|e| FormatError::BadLine { line, reason: format!("`{}`: {e}", s.trim()) })
+}
+
+/// Wires have to be distinct and exist, else the gate can't be undone
+pub fn check_wires(wires: &[usize], inp_size: usize, gate: usize) -> Result<(), FormatError> {
+    if let Some(w) = wires.iter().find(|&&w| w >= inp_size) {
+        return Err(FormatError::InvalidGate { gate, reason: format!("Wire {w} is out of range for inp_size {inp_size}") });
+    }
+    if !wires.iter().all_unique() {
+        return Err(FormatError::InvalidGate { gate, reason: format!("Wires {wires:?} aren't distinct") });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::circuit::ReversibleCircuit;
+
+    const HEADER: Header = Header { kind: Kind::Arbitrairy, gate_size: 4, inp_size: 400, gates: 2 };
+
+    #[test]
+    fn binary_header_roundtrips() {
+        let mut bytes = Vec::new();
+        write_header(&mut bytes, HEADER);
+        assert_eq!(read_header(&mut ByteReader::new(&bytes), Kind::Arbitrairy, 4).unwrap(), HEADER);
+        assert!(matches!(read_header(&mut ByteReader::new(&bytes), Kind::Fredkins, 0), Err(FormatError::WrongKind { .. })));
+        assert!(matches!(read_header(&mut ByteReader::new(&bytes), Kind::Arbitrairy, 2), Err(FormatError::WrongGateSize { expected: 2, found: 4 })));
+        assert!(matches!(read_header(&mut ByteReader::new(&bytes[..bytes.len() - 1]), Kind::Arbitrairy, 4), Err(FormatError::Truncated)));
+        bytes[4] = VERSION + 1;
+        assert!(matches!(read_header(&mut ByteReader::new(&bytes), Kind::Arbitrairy, 4), Err(FormatError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn text_skips_comments_and_blank_lines() {
+        let text = write_text_header(HEADER) + "# A comment\n\ngate 0 1 2 3 | 0  # trailing comment\n   gate 4 5 6 7 | 1\n";
+        let (header, gates) = read_text(&text, Kind::Arbitrairy, 4).unwrap();
+        assert_eq!(header, HEADER);
+        assert_eq!(gates, vec![(7, "0 1 2 3 | 0"), (8, "4 5 6 7 | 1")]);
+    }
+
+    #[test]
+    fn text_needs_every_field() {
+        let read = |text: &str| read_text(text, Kind::Arbitrairy, 4).map(|(header, _)| header);
+        assert!(matches!(read("kind arbitrairy"), Err(FormatError::BadMagic)));
+        assert!(matches!(read("reversible-program 2"), Err(FormatError::UnsupportedVersion(2))));
+        assert!(matches!(read("reversible-program 1\ngate_size 4\ninp_size 8\ngate 0"), Err(FormatError::MissingField("kind"))));
+        assert!(matches!(read("reversible-program 1\nkind arbitrairy\ninp_size 8\ngate 0"), Err(FormatError::MissingField("gate_size"))));
+        assert!(matches!(read("reversible-program 1\nkind arbitrairy\ngate_size 4\ngate 0"), Err(FormatError::MissingField("inp_size"))));
+        assert!(matches!(read("reversible-program 1\nkind arbitrairy\ngate_size 4\ninp_size 8"), Err(FormatError::NoGates)));
+        assert!(matches!(read("reversible-program 1\nkind arbitrairy\ngate_size four"), Err(FormatError::BadLine { line: 3, .. })));
+        assert!(matches!(read("reversible-program 1\nwires 8"), Err(FormatError::BadLine { line: 2, .. })));
+    }
+
+    #[test]
+    fn wires_have_to_be_distinct_and_in_range() {
+        assert!(check_wires(&[0, 7, 3], 8, 0).is_ok());
+        assert!(matches!(check_wires(&[0, 8, 3], 8, 5), Err(FormatError::InvalidGate { gate: 5, .. })));
+        assert!(matches!(check_wires(&[3, 0, 3], 8, 5), Err(FormatError::InvalidGate { gate: 5, .. })));
+    }
+
+    #[test]
+    fn peeks_without_parsing() {
+        let arbitrairy = crate::arbitrairy_program::Program::<2, 4>::new(8);
+        let fredkins = crate::fredkins_program::Program::new(8);
+        assert_eq!(peek(&arbitrairy.to_bytes()), Some((Kind::Arbitrairy, 2)));
+        assert_eq!(peek(arbitrairy.to_text().as_bytes()), Some((Kind::Arbitrairy, 2)));
+        assert_eq!(peek(&fredkins.to_bytes()), Some((Kind::Fredkins, 0)));
+        assert_eq!(peek(fredkins.to_text().as_bytes()), Some((Kind::Fredkins, 0)));
+        assert_eq!(peek(b"reversible-program 1\nkind arbitrairy\ninp_size 8\ngate 0 1 | 0 1 2 3"), None);
+        assert_eq!(peek(b"reversible-program 1\nkind toffolis\ninp_size 8"), None);
+    }
+}
@@ -0,0 +1,530 @@
+//! Compression of inputs of any length, by cutting them into inp_size blocks and running each through the program.
+//!
+//! Layout: MAGIC, VERSION (1 byte), program hash and inp_size (u64 LE each), how the payload is coded (1 byte, 0 for stripped or
+//! order + 1 for coded), then the blocks in one of two ways:
+//! - Stripped, with `codec::strip`: for each block its leading zero count plus one and trailing zero count (as LEB128
+//!   varints, since a u64 apiece would eat most of what a block saves) followed by its payload bits packed into zero padded bytes.
+//!   A 0 in place of the leading count ends the stream, followed by the original length in bits of the last block (0 if there
+//!   were none).
+//! - Coded: frames of up to FRAME_BLOCKS blocks, each being its block count, the original length in bits of its last block and the
+//!   length of its code in bytes (as varints) followed by the code, which is every block's whole output in turn coded by `arith`.
+//!   The model starts afresh each frame. A block count of 0 ends the stream.
+//!
+//! The end is followed by a CRC-32 (u32 LE) of everything before it. Only the last block can be shorter than inp_size.
+//! Version 1 had no payload byte, its blocks always being stripped. Versions 1 and 2 started each stripped block with its own
+//! original length in bits, a length of 0 ending the stream.
+
+use std::io::{self, Read, Write};
+
+use bit_vec::BitVec;
+use itertools::Itertools;
+
+use crate::arith;
+use crate::circuit::ReversibleCircuit;
+use crate::codec::{self, program_hash};
+use crate::container::{crc32_continue, ContainerError};
+use crate::fitness::Entropy;
+
+pub const MAGIC: [u8; 4] = *b"RVST";
+/// Older versions are still read, see above
+pub const VERSION: u8 = 3;
+/// Most blocks coded together, which bounds how much has to be held in memory while still giving the model plenty to learn from
+const FRAME_BLOCKS: usize = 1024;
+
+/// How each block's output gets stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Payload {
+    /// With the zero runs at either end thrown away, which is what the `edge_zeros` fitness scores
+    Stripped,
+    /// Arithmetic coded with a context of `order` bits, which is what the `coded` fitnesses score
+    Coded { order: usize }
+}
+
+impl Payload {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "stripped" => Some(Self::Stripped),
+            "coded" => Some(Self::Coded { order: 0 }),
+            _ => {
+                let order = name.strip_prefix("coded:")?.parse().ok().filter(|&k| k <= Entropy::MAX_ORDER)?;
+                Some(Self::Coded { order })
+            }
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Self::Stripped => 0,
+            Self::Coded { order } => order as u8 + 1
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte as usize {
+            0 => Some(Self::Stripped),
+            n if n - 1 <= Entropy::MAX_ORDER => Some(Self::Coded { order: n - 1 }),
+            _ => None
+        }
+    }
+}
+
+/// Passes everything through to `inner`, keeping a running CRC-32 of it
+struct Checksummed<T> {
+    inner: T,
+    crc: u32
+}
+
+impl<W: Write> Write for Checksummed<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc = crc32_continue(self.crc, &buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Checksummed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc = crc32_continue(self.crc, &buf[..n]);
+        Ok(n)
+    }
+}
+
+fn write_varint(out: &mut impl Write, mut n: u64) -> io::Result<()> {
+    while n >= 0x80 {
+        out.write_all(&[(n as u8) | 0x80])?;
+        n >>= 7;
+    }
+    out.write_all(&[n as u8])
+}
+
+fn read_varint(input: &mut impl Read) -> Result<u64, ContainerError> {
+    let mut n = 0u64;
+    for shift in (0..64).step_by(7) {
+        let mut byte = [0];
+        input.read_exact(&mut byte)?;
+        n |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(ContainerError::Io(io::Error::new(io::ErrorKind::InvalidData, "Varint is too long")))
+}
+
+fn write_stripped(out: &mut impl Write, program: &impl ReversibleCircuit, block: &BitVec) -> io::Result<()> {
+    let res = program.forward(block.clone());
+    let (leading, trailing) = codec::zero_runs(&res);
+    for n in [leading + 1, trailing] {
+        write_varint(out, n as u64)?;
+    }
+    out.write_all(&codec::strip(&res, leading, trailing).to_bytes())
+}
+
+fn write_frame(out: &mut impl Write, program: &impl ReversibleCircuit, order: usize, blocks: &[BitVec]) -> io::Result<()> {
+    let Some(last) = blocks.last() else { return Ok(()) };
+    write_coded(out, &program.forward_many(blocks), last.len(), order)
+}
+
+/// One frame of `outputs`, the last of which came from a block of `last_len` bits
+fn write_coded(out: &mut impl Write, outputs: &[BitVec], last_len: usize, order: usize) -> io::Result<()> {
+    let code = arith::encode_all(outputs, order);
+    for n in [outputs.len(), last_len, code.len()] {
+        write_varint(out, n as u64)?;
+    }
+    out.write_all(&code)
+}
+
+/// Bytes the frames of a coded payload take up, given the blocks and what the program turned them into. This is what the `coded`
+/// fitnesses score, so it's worked out by writing the frames just as `encode` would
+pub fn coded_len(inputs: &[BitVec], outputs: &[BitVec], order: usize) -> usize {
+    let mut frames = vec![];
+    for (inputs, outputs) in inputs.chunks(FRAME_BLOCKS).zip(outputs.chunks(FRAME_BLOCKS)) {
+        write_coded(&mut frames, outputs, inputs[inputs.len() - 1].len(), order).expect("Writing to a Vec can't fail");
+    }
+    frames.len()
+}
+
+/// Compresses everything `input` has to offer onto `output` a block at a time, returning how many bytes were read
+pub fn encode(program: &impl ReversibleCircuit, payload: Payload, input: &mut impl Read, output: &mut impl Write) -> io::Result<u64> {
+    let inp_size = program.inp_size();
+    let mut out = Checksummed { inner: output, crc: 0 };
+    out.write_all(&MAGIC)?;
+    out.write_all(&[VERSION])?;
+    out.write_all(&program_hash(program).to_le_bytes())?;
+    out.write_all(&(inp_size as u64).to_le_bytes())?;
+    out.write_all(&[payload.to_byte()])?;
+
+    // Blocks waiting to be coded together
+    let mut frame = vec![];
+    let mut last_len = 0;
+    let mut add_block = |out: &mut Checksummed<_>, block: BitVec| -> io::Result<()> {
+        last_len = block.len();
+        match payload {
+            Payload::Stripped => write_stripped(out, program, &block),
+            Payload::Coded { order } => {
+                frame.push(block);
+                if frame.len() < FRAME_BLOCKS {
+                    return Ok(());
+                }
+                write_frame(out, program, order, &std::mem::take(&mut frame))
+            }
+        }
+    };
+
+    let mut pending = BitVec::new();
+    let mut read = 0;
+    let mut buf = [0; 1 << 16];
+    loop {
+        let n = match input.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e)
+        };
+        read += n as u64;
+        pending.extend(BitVec::from_bytes(&buf[..n]).iter());
+        let full = pending.len() / inp_size * inp_size;
+        for chunk in &pending.iter().take(full).chunks(inp_size) {
+            add_block(&mut out, chunk.collect())?;
+        }
+        pending = pending.iter().skip(full).collect();
+    }
+    // The last, short block gets padded by `forward` just like any other undersized input
+    if !pending.is_empty() {
+        add_block(&mut out, pending)?;
+    }
+    if let Payload::Coded { order } = payload {
+        write_frame(&mut out, program, order, &frame)?;
+    }
+
+    write_varint(&mut out, 0)?;
+    if payload == Payload::Stripped {
+        write_varint(&mut out, last_len as u64)?;
+    }
+    let crc = out.crc;
+    out.inner.write_all(&crc.to_le_bytes())?;
+    Ok(read)
+}
+
+/// Decoded bits on their way out, written a whole byte at a time
+struct Unpacker<W> {
+    output: W,
+    pending: BitVec,
+    written: u64
+}
+
+impl<W: Write> Unpacker<W> {
+    fn push(&mut self, bits: impl Iterator<Item = bool>) -> io::Result<()> {
+        self.pending.extend(bits);
+        let whole = self.pending.len() / 8 * 8;
+        self.output.write_all(&self.pending.iter().take(whole).collect::<BitVec>().to_bytes())?;
+        self.written += whole as u64 / 8;
+        self.pending = self.pending.iter().skip(whole).collect();
+        Ok(())
+    }
+}
+
+/// Undoes `encode`, writing each block out as soon as it's decoded. This means a damaged stream can have written some
+/// output by the time its checksum is found not to match, so the output shouldn't be trusted unless this returns Ok
+pub fn decode(program: &impl ReversibleCircuit, input: &mut impl Read, output: &mut impl Write) -> Result<u64, ContainerError> {
+    let mut input = Checksummed { inner: input, crc: 0 };
+    let mut header = [0; 21];
+    input.read_exact(&mut header)?;
+    let magic: [u8; 4] = header[..4].try_into().unwrap();
+    if magic != MAGIC {
+        return Err(ContainerError::BadMagic(magic));
+    }
+    let version = header[4];
+    let payload = match version {
+        1 => Payload::Stripped,
+        2 | VERSION => {
+            let mut byte = [0];
+            input.read_exact(&mut byte)?;
+            Payload::from_byte(byte[0]).ok_or(ContainerError::UnsupportedPayload(byte[0]))?
+        }
+        version => return Err(ContainerError::UnsupportedVersion(version))
+    };
+    let hash = u64::from_le_bytes(header[5..13].try_into().unwrap());
+    let inp_size = u64::from_le_bytes(header[13..21].try_into().unwrap());
+    if hash != program_hash(program) || inp_size != program.inp_size() as u64 {
+        return Err(ContainerError::ProgramMismatch { expected: hash, found: program_hash(program) });
+    }
+
+    let mut out = Unpacker { output, pending: BitVec::new(), written: 0 };
+    match payload {
+        Payload::Stripped => decode_stripped(program, version < 3, &mut input, &mut out)?,
+        Payload::Coded { order } => decode_coded(program, order, &mut input, &mut out)?
+    }
+
+    let computed = input.crc;
+    let mut stored = [0; 4];
+    input.read_exact(&mut stored)?;
+    let stored = u32::from_le_bytes(stored);
+    if stored != computed {
+        return Err(ContainerError::ChecksumMismatch { stored, computed });
+    }
+    if !out.pending.is_empty() {
+        return Err(ContainerError::Io(io::Error::new(io::ErrorKind::InvalidData, "Blocks don't add up to a whole number of bytes")));
+    }
+    if input.read(&mut [0])? != 0 {
+        return Err(ContainerError::TrailingData);
+    }
+    Ok(out.written)
+}
+
+/// `lengths_per_block` is for versions before 3, where every block started with its own original length
+fn decode_stripped(program: &impl ReversibleCircuit, lengths_per_block: bool, input: &mut impl Read, out: &mut Unpacker<impl Write>) -> Result<(), ContainerError> {
+    let inp_size = program.inp_size() as u64;
+    let mut seen_short = false;
+    // Otherwise the latest block waits until it's known whether it's the last one, and so how much of it to keep
+    let mut held: Option<BitVec> = None;
+    loop {
+        let (original_bits, leading_zeros) = if lengths_per_block {
+            match read_varint(input)? {
+                0 => return Ok(()),
+                original_bits => (original_bits, read_varint(input)?)
+            }
+        } else {
+            match read_varint(input)? {
+                0 => break,
+                leading => (inp_size, leading - 1)
+            }
+        };
+        let trailing_zeros = read_varint(input)?;
+        let payload_bits = leading_zeros.checked_add(trailing_zeros).and_then(|stripped| inp_size.checked_sub(stripped));
+        let Some(payload_bits) = payload_bits.filter(|_| original_bits <= inp_size) else {
+            return Err(ContainerError::InconsistentLengths { original_bits, inp_size, leading_zeros, trailing_zeros, payload_bits: 0 });
+        };
+        if seen_short {
+            return Err(ContainerError::ShortBlockNotLast);
+        }
+        seen_short = original_bits < inp_size;
+
+        let mut payload = vec![0; payload_bits.div_ceil(8) as usize];
+        input.read_exact(&mut payload)?;
+        let mut payload = BitVec::from_bytes(&payload);
+        if payload.iter().skip(payload_bits as usize).any(|b| b) {
+            return Err(ContainerError::NonZeroPadding);
+        }
+        payload.truncate(payload_bits as usize);
+
+        let block = program.backward(codec::unstrip(leading_zeros as usize, &payload, trailing_zeros as usize));
+        if lengths_per_block {
+            out.push(block.iter().take(original_bits as usize))?;
+        } else if let Some(previous) = held.replace(block) {
+            out.push(previous.iter())?;
+        }
+    }
+    let last_bits = read_varint(input)?;
+    match held {
+        None if last_bits == 0 => {}
+        Some(block) if (1..=inp_size).contains(&last_bits) => out.push(block.iter().take(last_bits as usize))?,
+        _ => return Err(ContainerError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("Stream can't end in a block of {last_bits} bits"))))
+    }
+    Ok(())
+}
+
+fn decode_coded(program: &impl ReversibleCircuit, order: usize, input: &mut impl Read, out: &mut Unpacker<impl Write>) -> Result<(), ContainerError> {
+    let inp_size = program.inp_size();
+    let mut seen_short = false;
+    loop {
+        let blocks = read_varint(input)?;
+        if blocks == 0 {
+            return Ok(());
+        }
+        let [last_bits, code_len] = [read_varint(input)?, read_varint(input)?];
+        if blocks > FRAME_BLOCKS as u64 || last_bits == 0 || last_bits > inp_size as u64 {
+            return Err(ContainerError::Io(io::Error::new(io::ErrorKind::InvalidData, format!("Frame of {blocks} blocks can't end in one of {last_bits} bits"))));
+        }
+        if seen_short {
+            return Err(ContainerError::ShortBlockNotLast);
+        }
+        seen_short = last_bits < inp_size as u64;
+
+        // Read through `take` rather than into a buffer of code_len, which could be anything if the stream's damaged
+        let mut code = vec![];
+        input.take(code_len).read_to_end(&mut code)?;
+        if code.len() as u64 != code_len {
+            return Err(ContainerError::Io(io::Error::new(io::ErrorKind::UnexpectedEof, "Frame is truncated")));
+        }
+        let mut decoder = arith::Decoder::new(&code, order);
+        for i in 0..blocks {
+            let len = if i + 1 == blocks { last_bits as usize } else { inp_size };
+            out.push(program.backward(decoder.decode(inp_size)).iter().take(len))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::arbitrairy_program::Program;
+    use crate::container::crc32;
+    use crate::fredkins_program;
+
+    const PAYLOADS: [Payload; 3] = [Payload::Stripped, Payload::Coded { order: 0 }, Payload::Coded { order: 3 }];
+
+    fn encoded(program: &impl ReversibleCircuit, payload: Payload, data: &[u8]) -> Vec<u8> {
+        let mut compressed = vec![];
+        assert_eq!(encode(program, payload, &mut &data[..], &mut compressed).unwrap(), data.len() as u64);
+        compressed
+    }
+
+    fn decoded(program: &impl ReversibleCircuit, compressed: &[u8]) -> Result<Vec<u8>, ContainerError> {
+        let mut data = vec![];
+        let written = decode(program, &mut &compressed[..], &mut data)?;
+        assert_eq!(written, data.len() as u64);
+        Ok(data)
+    }
+
+    /// Random inputs of up to a few blocks, including empty ones and exact multiples of a block, through a lineage of mutated programs
+    #[test]
+    fn roundtrips() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let mut p = Program::<4, 16>::new(400);
+        for _ in 0..30 {
+            p = p.mutate(0.3, &mut rng);
+            for (len, payload) in [0, 50, 150, rng.random_range(0..=200)].into_iter().cartesian_product(PAYLOADS) {
+                let data: Vec<u8> = (0..len).map(|_| rng.random()).collect();
+                let compressed = encoded(&p, payload, &data);
+                assert_eq!(decoded(&p, &compressed).unwrap(), data, "decode(encode(x)) != x with {payload:?}");
+
+                let mut corrupted = compressed.clone();
+                corrupted[rng.random_range(0..compressed.len())] ^= 1 << rng.random_range(0..8);
+                assert!(decoded(&p, &corrupted).is_err(), "Corrupted stream was accepted with {payload:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn streams_any_kind_of_circuit() {
+        let p = fredkins_program::Program::new(64);
+        let data = (0..=255).collect::<Vec<u8>>();
+        for payload in PAYLOADS {
+            assert_eq!(decoded(&p, &encoded(&p, payload, &data)).unwrap(), data);
+            assert!(matches!(decoded(&Program::<4, 16>::new(64), &encoded(&p, payload, &data)), Err(ContainerError::ProgramMismatch { .. })));
+        }
+    }
+
+    #[test]
+    fn refuses_truncated_streams() {
+        let p = Program::<4, 16>::new(64);
+        for payload in PAYLOADS {
+            let mut compressed = encoded(&p, payload, &[7; 20]);
+            for len in 0..compressed.len() {
+                assert!(decoded(&p, &compressed[..len]).is_err(), "Accepted the first {len} bytes with {payload:?}");
+            }
+            compressed.push(0);
+            assert!(matches!(decoded(&p, &compressed), Err(ContainerError::TrailingData)));
+        }
+    }
+
+    /// A stripped stream the way versions 1 and 2 wrote them, with every block starting with its own length
+    fn old_stripped(program: &impl ReversibleCircuit, version: u8, blocks: &[BitVec]) -> Vec<u8> {
+        let mut stream = Checksummed { inner: vec![], crc: 0 };
+        stream.write_all(&MAGIC).unwrap();
+        stream.write_all(&[version]).unwrap();
+        stream.write_all(&program_hash(program).to_le_bytes()).unwrap();
+        stream.write_all(&(program.inp_size() as u64).to_le_bytes()).unwrap();
+        if version == 2 {
+            stream.write_all(&[Payload::Stripped.to_byte()]).unwrap();
+        }
+        for block in blocks {
+            let res = program.forward(block.clone());
+            let (leading, trailing) = codec::zero_runs(&res);
+            [block.len(), leading, trailing].into_iter().for_each(|n| write_varint(&mut stream, n as u64).unwrap());
+            stream.write_all(&codec::strip(&res, leading, trailing).to_bytes()).unwrap();
+        }
+        write_varint(&mut stream, 0).unwrap();
+        let crc = stream.crc;
+        stream.inner.extend(crc.to_le_bytes());
+        stream.inner
+    }
+
+    /// Old streams gave every block a length, so a short one could turn up anywhere. The checksum is right, and the only thing
+    /// wrong is the first block being short
+    #[test]
+    fn only_the_last_block_can_be_short() {
+        let p = Program::<4, 16>::new(8);
+        let blocks = vec![BitVec::from_elem(4, true); 2];
+        assert!(matches!(decoded(&p, &old_stripped(&p, 2, &blocks)), Err(ContainerError::ShortBlockNotLast)));
+    }
+
+    /// Nothing but the length at the end says how much of the last block to keep, so it has to fit in one
+    #[test]
+    fn refuses_impossible_last_lengths() {
+        let p = Program::<4, 16>::new(64);
+        for (data, last_len, bad_lens) in [(vec![3; 20], 32, [0, 65]), (vec![], 0, [1, 64])] {
+            let good = encoded(&p, Payload::Stripped, &data);
+            let end = good.len() - 5;
+            assert_eq!(good[end], last_len);
+            for bad_len in bad_lens {
+                let mut bad = good[..end].to_vec();
+                bad.push(bad_len);
+                bad.extend(crc32(&bad).to_le_bytes());
+                assert!(decoded(&p, &bad).is_err(), "Accepted a last block of {bad_len} bits");
+            }
+        }
+    }
+
+    #[test]
+    fn coded_frames_roundtrip() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let p = (0..5).fold(Program::<4, 16>::new(16), |p, _| p.mutate(0.3, &mut rng));
+        // Two full frames and a bit of a third, ending in a short block
+        let data: Vec<u8> = (0..(2 * FRAME_BLOCKS + 10) * 2 + 1).map(|_| rng.random()).collect();
+        assert!(decoded(&p, &encoded(&p, Payload::Coded { order: 2 }, &data)).unwrap() == data);
+    }
+
+    /// Coding is what makes skewed outputs pay off even when they don't happen to have long zero runs at their ends
+    #[test]
+    fn coded_payload_cashes_in_skew() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let p = Program::<4, 16>::new(64);
+        let data: Vec<u8> = (0..4000).map(|_| if rng.random_bool(0.05) { 0x81 } else { 1 }).collect();
+        let (stripped, coded) = (encoded(&p, Payload::Stripped, &data), encoded(&p, Payload::Coded { order: 8 }, &data));
+        assert!(coded.len() * 4 < data.len().min(stripped.len()), "Coded into {} bytes against {} stripped", coded.len(), stripped.len());
+    }
+
+    #[test]
+    fn reads_old_versions() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let p = (0..5).fold(Program::<4, 16>::new(64), |p, _| p.mutate(0.3, &mut rng));
+        let data: Vec<u8> = (0..20).map(|_| rng.random()).collect();
+        let blocks = BitVec::from_bytes(&data).iter().chunks(64).into_iter().map(|chunk| chunk.collect()).collect_vec();
+        for version in [1, 2] {
+            assert_eq!(decoded(&p, &old_stripped(&p, version, &blocks)).unwrap(), data, "Misread version {version}");
+        }
+        // Each block saves a varint on the old layout, bar the length at the end
+        assert_eq!(encoded(&p, Payload::Stripped, &data).len() + 2, old_stripped(&p, 2, &blocks).len());
+    }
+
+    #[test]
+    fn payloads_by_name() {
+        for payload in PAYLOADS.into_iter().chain([Payload::Coded { order: Entropy::MAX_ORDER }]) {
+            assert_eq!(Payload::from_byte(payload.to_byte()), Some(payload));
+        }
+        assert_eq!(Payload::from_name("coded:3"), Some(Payload::Coded { order: 3 }));
+        assert_eq!(Payload::from_name("stripped"), Some(Payload::Stripped));
+        assert_eq!(Payload::from_name("coded:21"), None);
+        assert_eq!(Payload::from_byte(Entropy::MAX_ORDER as u8 + 2), None);
+    }
+
+    #[test]
+    fn varints_roundtrip() {
+        for n in [0, 1, 0x7f, 0x80, 0x3fff, 0x4000, u32::MAX as u64, u64::MAX] {
+            let mut bytes = vec![];
+            write_varint(&mut bytes, n).unwrap();
+            assert_eq!(bytes.len(), (64 - n.leading_zeros() as usize).max(1).div_ceil(7));
+            assert_eq!(read_varint(&mut bytes.as_slice()).unwrap(), n);
+        }
+        assert!(read_varint(&mut [0xff; 10].as_slice()).is_err());
+    }
+}
@@ -0,0 +1,118 @@
+//! Wire states part way through a program on a fixed set of inputs. Mutations mostly touch a few gates, so a child that's the same as
+//! its parent up to some gate can carry on from the parent's state there rather than running every gate again
+
+use bit_vec::BitVec;
+
+use crate::circuit::{ReversibleCircuit, Sliced};
+
+/// The states a program reaches on some inputs, packed as `Sliced`
+#[derive(Clone, Debug)]
+pub struct Trace {
+    /// Gates between the states kept, or 0 to only keep the end
+    every: usize,
+    /// (gates run, wires after them), gates run increasing and always ending with the whole program's
+    states: Vec<(usize, Sliced)>
+}
+
+impl Trace {
+    /// Runs the whole of `program` over `start`, its inputs packed
+    pub fn new<C: ReversibleCircuit>(program: &C, start: &Sliced, every: usize) -> Self {
+        Self::resume(program, vec![], start, every)
+    }
+
+    /// Runs `program` on from the last of `states`, or from `start` if there are none
+    fn resume<C: ReversibleCircuit>(program: &C, mut states: Vec<(usize, Sliced)>, start: &Sliced, every: usize) -> Self {
+        let end = program.gate_count();
+        loop {
+            let (gate, state) = states.last().map_or((0, start), |(gate, state)| (*gate, state));
+            if gate == end {
+                if states.is_empty() {
+                    states.push((0, start.clone()));
+                }
+                break;
+            }
+            let next = gate.checked_div(every).map_or(end, |n| ((n + 1) * every).min(end));
+            let mut state = state.clone();
+            program.forward_gates(&mut state, gate..next);
+            states.push((next, state));
+        }
+        // Whatever the parent's end was isn't worth keeping once it's no longer the end
+        states.retain(|&(gate, _)| gate == end || (every > 0 && gate % every == 0));
+        Self { every, states }
+    }
+
+    /// Outputs of the program traced
+    pub fn outputs(&self) -> Vec<BitVec> {
+        self.states.last().unwrap().1.unpack()
+    }
+
+    /// Index of the last state `program` gets to as well, the program traced being `parent`
+    fn last_shared<C: ReversibleCircuit>(&self, parent: &C, program: &C) -> Option<usize> {
+        let shared = parent.shared_prefix(program);
+        self.states.iter().rposition(|&(gate, _)| gate <= shared)
+    }
+
+    /// Outputs of `program` on the same inputs, it being `parent` (the program traced) with some gates changed. Only the gates after
+    /// the last state they share get run
+    pub fn outputs_of<C: ReversibleCircuit>(&self, parent: &C, program: &C, start: &Sliced) -> Vec<BitVec> {
+        let (gate, mut state) = match self.last_shared(parent, program) {
+            Some(i) => self.states[i].clone(),
+            None => (0, start.clone())
+        };
+        program.forward_gates(&mut state, gate..program.gate_count());
+        state.unpack()
+    }
+
+    /// Traces `program` like `outputs_of` runs it, keeping the states it shares with `parent`
+    pub fn retrace<C: ReversibleCircuit>(&self, parent: &C, program: &C, start: &Sliced) -> Self {
+        let shared = self.last_shared(parent, program).map_or(0, |i| i + 1);
+        Self::resume(program, self.states[..shared].to_vec(), start, self.every)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+    use crate::crossover::{Alignment, Crossover, Points};
+    use crate::{arbitrairy_program, fredkins_program};
+
+    /// Runs a lineage of mutated and crossed programs on from their parents' traces, which has to give exactly what running them from
+    /// scratch does, however far apart the traces keep their states
+    fn runs_on_from_the_parent<C: ReversibleCircuit>() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let inputs: Vec<BitVec> = (0..150).map(|_| (0..rng.random_range(0..=200)).map(|_| rng.random_bool(0.5)).collect()).collect();
+        let start = Sliced::pack::<C>(&inputs, 200);
+        for every in [0, 1, 3, 16] {
+            let mut parent = C::random(200, 10, &mut rng);
+            let mut trace = Trace::new(&parent, &start, every);
+            assert!(trace.outputs() == parent.forward_many(&inputs), "Trace disagreed with forward_many");
+            for _ in 0..30 {
+                let mut child = parent.mutate(0.3, &mut rng);
+                if rng.random_bool(0.3) {
+                    child = child.crossover(&C::random(200, 10, &mut rng), Crossover { points: Points::Two, alignment: Alignment::Position }, &mut rng);
+                }
+                let expected = child.forward_many(&inputs);
+                assert!(trace.outputs_of(&parent, &child, &start) == expected, "Running on from the parent's trace disagreed with forward_many");
+                trace = trace.retrace(&parent, &child, &start);
+                assert!(trace.outputs() == expected, "Retraced outputs disagreed with forward_many");
+                let end = child.gate_count();
+                assert!(trace.states.iter().all(|&(gate, _)| gate == end || gate % every == 0), "Kept a state off the grid");
+                assert_eq!(trace.states.last().unwrap().0, end);
+                parent = child;
+            }
+        }
+    }
+
+    #[test]
+    fn arbitrairy_runs_on_from_the_parent() {
+        runs_on_from_the_parent::<arbitrairy_program::Program<4, 16>>();
+    }
+
+    #[test]
+    fn fredkins_runs_on_from_the_parent() {
+        runs_on_from_the_parent::<fredkins_program::Program>();
+    }
+}